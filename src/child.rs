@@ -0,0 +1,722 @@
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::fs::{File, OpenOptions};
+use std::future::{self, Future};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::SystemTime;
+
+use bytes::Buf;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::UnixStream,
+    runtime::Handle,
+    time::{self, Instant},
+};
+use tokio_pipe::{PipeRead, PipeWrite};
+
+use crate::commands::MuxRespExit;
+use crate::options::{OutputLimit, OutputSource, SessionOptions, TimeoutPhase};
+use crate::remote_command::quote;
+use crate::session::{
+    check_mux_alive, hello, new_session, read_ssh_pipe, run, wait,
+    LimitedResult, MuxError,
+};
+use crate::SshctlError;
+
+/// Describes what to connect to a stdio stream of a remote command.
+#[derive(PartialEq, Debug, Clone, Copy, Eq)]
+pub struct Stdio(StdioKind);
+
+#[derive(PartialEq, Debug, Clone, Copy, Eq)]
+enum StdioKind {
+    Piped,
+    Null,
+    Inherit,
+}
+
+impl Stdio {
+    /// Connects the stream to a pipe which is available from the
+    /// `RemoteChild`. This is the default.
+    pub fn piped() -> Self {
+        Self(StdioKind::Piped)
+    }
+
+    /// Connects the stream to `/dev/null` on the local host.
+    pub fn null() -> Self {
+        Self(StdioKind::Null)
+    }
+
+    /// Connects the stream to the corresponding stdio stream
+    /// of the local process.
+    pub fn inherit() -> Self {
+        Self(StdioKind::Inherit)
+    }
+
+    /// Returns the file descriptor owner which is sent to the
+    /// SSH master and the local end of the stdin pipe, if any.
+    pub(crate) fn input(&self) -> io::Result<(RemoteFd, Option<PipeWrite>)> {
+        match self.0 {
+            StdioKind::Piped => {
+                let (remote, local) = tokio_pipe::pipe()?;
+                Ok((RemoteFd::Read(remote), Some(local)))
+            }
+            StdioKind::Null => Ok((
+                RemoteFd::File(
+                    OpenOptions::new().read(true).open("/dev/null")?,
+                ),
+                None,
+            )),
+            StdioKind::Inherit => {
+                Ok((RemoteFd::Inherit(io::stdin().as_raw_fd()), None))
+            }
+        }
+    }
+
+    /// Returns the file descriptor owner which is sent to the
+    /// SSH master and the local end of the output pipe, if any.
+    /// `inherit` is the local file descriptor for `Stdio::inherit`.
+    pub(crate) fn output(
+        &self,
+        inherit: RawFd,
+    ) -> io::Result<(RemoteFd, Option<PipeRead>)> {
+        match self.0 {
+            StdioKind::Piped => {
+                let (local, remote) = tokio_pipe::pipe()?;
+                Ok((RemoteFd::Write(remote), Some(local)))
+            }
+            StdioKind::Null => Ok((
+                RemoteFd::File(
+                    OpenOptions::new().write(true).open("/dev/null")?,
+                ),
+                None,
+            )),
+            StdioKind::Inherit => Ok((RemoteFd::Inherit(inherit), None)),
+        }
+    }
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self::piped()
+    }
+}
+
+/// Keeps a file descriptor open until it was sent to the SSH master.
+pub(crate) enum RemoteFd {
+    Read(PipeRead),
+    Write(PipeWrite),
+    File(File),
+    Inherit(RawFd),
+}
+
+impl AsRawFd for RemoteFd {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Read(pipe) => pipe.as_raw_fd(),
+            Self::Write(pipe) => pipe.as_raw_fd(),
+            Self::File(file) => file.as_raw_fd(),
+            Self::Inherit(fd) => *fd,
+        }
+    }
+}
+
+/// The stdin of a remote command. Dropping it signals EOF to the
+/// remote command.
+#[derive(Debug)]
+pub struct ChildStdin(PipeWrite);
+
+/// The stdout of a remote command.
+#[derive(Debug)]
+pub struct ChildStdout {
+    pipe: PipeRead,
+    // Set until the PID line was looked for at the start of the output.
+    marker: Option<Arc<PidMarker>>,
+    // Output which was read while looking for the PID line.
+    buffer: Vec<u8>,
+}
+
+/// The stderr of a remote command.
+#[derive(Debug)]
+pub struct ChildStderr(PipeRead);
+
+impl AsyncWrite for ChildStdin {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl ChildStdout {
+    fn new(pipe: PipeRead, marker: Option<Arc<PidMarker>>) -> Self {
+        Self {
+            pipe,
+            marker,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Reads until it is known whether the output starts with the
+    /// PID line and strips it. Any other output is kept in the buffer.
+    fn poll_pid(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(marker) = self.marker.clone() {
+            match parse_pid_line(marker.prefix.as_bytes(), &self.buffer) {
+                PidLine::Incomplete => {
+                    let mut data = [0; 1024];
+                    let mut buf = ReadBuf::new(&mut data);
+                    ready!(Pin::new(&mut self.pipe).poll_read(cx, &mut buf))?;
+                    if buf.filled().is_empty() {
+                        self.marker = None;
+                    }
+                    self.buffer.extend_from_slice(buf.filled());
+                }
+                PidLine::Mismatch => self.marker = None,
+                PidLine::Found(length, pid) => {
+                    *marker.pid.lock().unwrap() = Some(pid);
+                    self.buffer.drain(..length);
+                    self.marker = None;
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for ChildStdout {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_pid(cx))?;
+
+        if !this.buffer.is_empty() {
+            let count = cmp::min(this.buffer.len(), buf.remaining());
+            buf.put_slice(&this.buffer[..count]);
+            this.buffer.drain(..count);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.pipe).poll_read(cx, buf)
+    }
+}
+
+impl AsyncRead for ChildStderr {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+/// A remote command which was started by `spawn`.
+///
+/// The stdio streams can be taken out of the child and used
+/// independently of it.
+///
+/// The remote command runs in its own process group. Signals are sent
+/// to the whole group, so pipelines and other child processes of the
+/// remote command are stopped as well.
+/// If the child is dropped while the remote command is still running,
+/// the process group is killed on the current tokio runtime. This also
+/// applies to cancelled `run` futures.
+/// Use `detach` to keep the remote command running.
+#[derive(Debug)]
+pub struct RemoteChild {
+    /// The stdin of the remote command. It is closed by `wait`.
+    pub stdin: Option<ChildStdin>,
+    /// The stdout of the remote command.
+    pub stdout: Option<ChildStdout>,
+    /// The stderr of the remote command.
+    pub stderr: Option<ChildStderr>,
+    ctlpath: String,
+    socket: UnixStream,
+    session_id: u32,
+    marker: Option<Arc<PidMarker>>,
+    deadline: Option<Instant>,
+    limits: [Option<OutputLimit>; 2],
+    exit_code: Option<u32>,
+}
+
+impl RemoteChild {
+    /// Returns the process ID of the remote command if it is already
+    /// known. It is reported at the start of stdout, see `pid`.
+    pub fn id(&self) -> Option<u32> {
+        self.marker
+            .as_ref()
+            .and_then(|marker| *marker.pid.lock().unwrap())
+    }
+
+    /// Waits until the remote command reported its process ID and
+    /// returns it. The PID is only reported if stdout is piped,
+    /// `SessionOptions::report_pid` is not disabled and the remote shell
+    /// ran the command wrapper, e.g. no `ForceCommand` replaced it.
+    ///
+    /// If stdout is still owned by the child, it is read until the PID
+    /// is known. Other output stays available from stdout.
+    pub async fn pid(&mut self) -> Result<Option<u32>, SshctlError> {
+        if let Some(stdout) = self.stdout.as_mut() {
+            future::poll_fn(|cx| stdout.poll_pid(cx)).await?;
+        }
+        Ok(self.id())
+    }
+
+    /// Sends the given signal, e.g. "TERM" or "INT", to the process
+    /// group of the remote command. The signal is delivered by the
+    /// `kill` command of the remote host in a second session.
+    pub async fn signal(&mut self, signal: &str) -> Result<(), SshctlError> {
+        let pid = match self.pid().await? {
+            Some(pid) => pid,
+            None => {
+                return Err(MuxError::new("Remote PID is unknown".into()).into())
+            }
+        };
+
+        let result = run(&self.ctlpath, &signal_command(signal, pid)?).await?;
+        if result.exit_code != 0 {
+            return Err(MuxError::new(format!(
+                "Sending signal {} to remote process group {} failed: {}",
+                signal,
+                pid,
+                String::from_utf8_lossy(&result.stderr).trim_end()
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Forces the remote command and all processes in its process group
+    /// to exit by sending `SIGKILL` and waits for it.
+    pub async fn kill(&mut self) -> Result<(), SshctlError> {
+        self.signal("KILL").await?;
+        self.wait().await.map(|_| ())
+    }
+
+    /// Releases the child without killing the remote command.
+    /// It keeps running until it exits on its own.
+    pub fn detach(mut self) {
+        self.marker = None;
+    }
+
+    /// Closes stdin and waits for the remote command to exit.
+    /// Returns the exit code of the remote command.
+    /// If the execution timeout of the session is exceeded, the process
+    /// group of the remote command is killed, the session is closed and
+    /// `SshctlError::Timeout` is returned.
+    ///
+    /// Stdout and stderr should be read concurrently, otherwise the
+    /// remote command may block on full pipes and never exit.
+    pub async fn wait(&mut self) -> Result<u32, SshctlError> {
+        match until(self.deadline, self.wait_exit()).await {
+            Some(x) => x,
+            None => Err(self.timed_out().await),
+        }
+    }
+
+    /// Closes stdin, waits for the remote command to exit and
+    /// collects all remaining output of stdout and stderr.
+    /// The execution timeout of the session applies to both.
+    pub async fn wait_with_output(
+        mut self,
+    ) -> Result<LimitedResult, SshctlError> {
+        match until(self.deadline, self.collect_output()).await {
+            Some(x) => x,
+            None => Err(self.timed_out().await),
+        }
+    }
+
+    /// Returns the end of the execution timeout, if any.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Same as `wait` but without the execution timeout.
+    pub(crate) async fn wait_exit(&mut self) -> Result<u32, SshctlError> {
+        self.stdin = None;
+
+        if let Some(exit_code) = self.exit_code {
+            return Ok(exit_code);
+        }
+
+        let exit_code = wait(&mut self.socket, self.session_id).await?;
+        self.exit_code = Some(exit_code);
+        Ok(exit_code)
+    }
+
+    /// Same as `wait_with_output` but without the execution timeout.
+    pub(crate) async fn collect_output(
+        &mut self,
+    ) -> Result<LimitedResult, SshctlError> {
+        let stdout = self.stdout.take();
+        let stderr = self.stderr.take();
+        let [stdout_limit, stderr_limit] = self.limits;
+        let (ctlpath, marker) = (self.ctlpath.clone(), self.marker.clone());
+
+        let (rx_rc, rx_stdout, rx_stderr) = tokio::join! {
+            self.wait_exit(),
+            read_output(
+                stdout,
+                stdout_limit,
+                OutputSource::Stdout,
+                &ctlpath,
+                &marker,
+            ),
+            read_output(
+                stderr,
+                stderr_limit,
+                OutputSource::Stderr,
+                &ctlpath,
+                &marker,
+            ),
+        };
+
+        let (stdout, stdout_truncated) = rx_stdout?;
+        let (stderr, stderr_truncated) = rx_stderr?;
+
+        Ok(LimitedResult {
+            stdout,
+            stderr,
+            exit_code: rx_rc?,
+            stdout_truncated,
+            stderr_truncated,
+        })
+    }
+
+    /// Stops the remote command after the execution timeout was exceeded
+    /// and returns the timeout error. The process group is killed if its
+    /// PID is known. The session is closed in any case, so a remote
+    /// command with unknown PID notices it on its next stdio access.
+    pub(crate) async fn timed_out(&mut self) -> SshctlError {
+        if self.received_pid().await.is_some() {
+            // Boxed because signal waits for a child itself.
+            // The timeout is reported even if the signal failed.
+            let _ = Box::pin(self.signal("KILL")).await;
+        }
+
+        self.marker = None;
+        self.stdin = None;
+        self.stdout = None;
+        self.stderr = None;
+        let _ = self.socket.shutdown().await;
+
+        SshctlError::Timeout(TimeoutPhase::Execution)
+    }
+
+    /// Returns true if the SSH master reported the exit of the remote
+    /// command or closed the session, without waiting for it.
+    fn session_ended(&mut self) -> bool {
+        let mut data = Vec::new();
+        let mut buffer = [0; 256];
+        loop {
+            match self.socket.try_read(&mut buffer) {
+                Ok(0) => return true,
+                Ok(count) => data.extend_from_slice(&buffer[..count]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => return true,
+            }
+        }
+
+        let mut packets = data.as_slice();
+        while packets.remaining() >= 4 {
+            let length = packets.get_u32() as usize;
+            if packets.remaining() < length {
+                break;
+            }
+            if let Ok(response) =
+                MuxRespExit::deserialize(&mut &packets[..length])
+            {
+                if response.is_valid(self.session_id) {
+                    return true;
+                }
+            }
+            packets.advance(length);
+        }
+        false
+    }
+
+    /// Returns the process ID of the remote command if its PID line
+    /// was already received, without waiting for it.
+    async fn received_pid(&mut self) -> Option<u32> {
+        if let Some(stdout) = self.stdout.as_mut() {
+            let _ =
+                future::poll_fn(|cx| Poll::Ready(stdout.poll_pid(cx))).await;
+        }
+        self.id()
+    }
+}
+
+impl Drop for RemoteChild {
+    fn drop(&mut self) {
+        let marker = match (&self.marker, self.exit_code) {
+            (Some(marker), None) => marker.clone(),
+            _ => return,
+        };
+        if self.session_ended() {
+            return;
+        }
+
+        // Without a runtime, the remote command is not killed. It only
+        // notices the closed session when it uses its stdio streams.
+        let handle = match Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+
+        let ctlpath = self.ctlpath.clone();
+        let stdout = self.stdout.take();
+        handle.spawn(async move {
+            // The PID line may not have been read yet.
+            if let Some(mut stdout) = stdout {
+                let _ = future::poll_fn(|cx| stdout.poll_pid(cx)).await;
+            }
+
+            let pid = match *marker.pid.lock().unwrap() {
+                Some(pid) => pid,
+                None => return,
+            };
+            // The process group may have exited in the meantime,
+            // so the result is ignored.
+            if let Ok(command) = signal_command("KILL", pid) {
+                let _ = run(&ctlpath, &command).await;
+            }
+        });
+    }
+}
+
+/// Starts a given shell command on the remote hosts default shell
+/// though an existing SSH UNIX control socket.
+/// The SSH control socket is created
+/// outside of this crate by an existing SSH connection.
+///
+/// Unlike `run`, the output of the remote command is not buffered.
+/// It is available as async streams from the returned child.
+///
+/// Unless disabled by `SessionOptions::report_pid`, the command is
+/// wrapped to report its PID, which requires a POSIX compatible login
+/// shell on the remote host.
+pub async fn spawn(
+    ctlpath: &str,
+    command: &str,
+    options: &SessionOptions,
+) -> Result<RemoteChild, SshctlError> {
+    spawn_stdio(ctlpath, command, options, &[Stdio::piped(); 3]).await
+}
+
+/// Same as `spawn` but stdin, stdout and stderr are connected
+/// as described by `stdio`.
+pub(crate) async fn spawn_stdio(
+    ctlpath: &str,
+    command: &str,
+    options: &SessionOptions,
+    stdio: &[Stdio; 3],
+) -> Result<RemoteChild, SshctlError> {
+    let mut socket =
+        timeout(options, TimeoutPhase::Connect, UnixStream::connect(ctlpath))
+            .await?;
+
+    // The remote PID is reported in the first line of stdout. The line
+    // starts with a random prefix, so it is not confused with output
+    // if the remote shell did not run the wrapper.
+    let marker = match stdio[1] == Stdio::piped() && options.pid_reporting() {
+        true => Some(Arc::new(PidMarker::new())),
+        false => None,
+    };
+    let command = match &marker {
+        Some(marker) => format!(
+            "printf '{}%d\\n' $$; exec \"${{SHELL:-/bin/sh}}\" -c {}",
+            marker.prefix,
+            quote(command)
+        ),
+        None => command.into(),
+    };
+
+    timeout(options, TimeoutPhase::Hello, async {
+        hello(&mut socket).await?;
+        check_mux_alive(&mut socket, 0).await
+    })
+    .await?;
+
+    let request_id = 1;
+    let (session_id, local_stdin, local_stdout, local_stderr) = timeout(
+        options,
+        TimeoutPhase::SessionOpen,
+        new_session(&mut socket, request_id, &command, options, stdio),
+    )
+    .await?;
+
+    let deadline = options
+        .timeout(TimeoutPhase::Execution)
+        .map(|timeout| Instant::now() + timeout);
+
+    Ok(RemoteChild {
+        stdin: local_stdin.map(ChildStdin),
+        stdout: local_stdout
+            .map(|stdout| ChildStdout::new(stdout, marker.clone())),
+        stderr: local_stderr.map(ChildStderr),
+        ctlpath: ctlpath.into(),
+        socket,
+        session_id,
+        marker,
+        deadline,
+        limits: options.output_limits(),
+        exit_code: None,
+    })
+}
+
+/// Runs `future` until `deadline`, if any.
+/// Returns `None` if the deadline passed before it completed.
+pub(crate) async fn until<F: Future>(
+    deadline: Option<Instant>,
+    future: F,
+) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Runs the given phase of a session with the timeout from `options`.
+async fn timeout<F, T, E>(
+    options: &SessionOptions,
+    phase: TimeoutPhase,
+    future: F,
+) -> Result<T, SshctlError>
+where
+    F: Future<Output = Result<T, E>>,
+    SshctlError: From<E>,
+{
+    match options.timeout(phase) {
+        Some(duration) => match time::timeout(duration, future).await {
+            Ok(x) => Ok(x?),
+            Err(_) => Err(SshctlError::Timeout(phase)),
+        },
+        None => Ok(future.await?),
+    }
+}
+
+/// Returns the remote command which sends `signal` to the process group
+/// of `pid`.
+fn signal_command(signal: &str, pid: u32) -> Result<String, SshctlError> {
+    if signal.is_empty() || !signal.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(
+            MuxError::new(format!("Invalid signal: {:?}", signal)).into()
+        );
+    }
+
+    // sshd starts the remote shell in a new session, so its PID is also
+    // the ID of its process group.
+    Ok(format!("kill -s {} -- -{}", signal, pid))
+}
+
+/// The PID line which is written by the command wrapper before
+/// the remote command starts.
+#[derive(Debug)]
+struct PidMarker {
+    prefix: String,
+    pid: Mutex<Option<u32>>,
+}
+
+impl PidMarker {
+    fn new() -> Self {
+        let mut hasher = RandomState::new().build_hasher();
+        if let Ok(time) =
+            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+        {
+            hasher.write_u128(time.as_nanos());
+        }
+
+        Self {
+            prefix: format!("ssh-muxcontrol-{:016x} ", hasher.finish()),
+            pid: Mutex::new(None),
+        }
+    }
+}
+
+/// Result of matching the start of stdout against the PID line.
+#[derive(PartialEq, Debug, Clone, Copy, Eq)]
+pub(crate) enum PidLine {
+    /// More output is required to decide.
+    Incomplete,
+    /// The output does not start with the PID line.
+    Mismatch,
+    /// The PID line with the given length including its line ending.
+    Found(usize, u32),
+}
+
+/// Checks if `data` starts with a line which consists of `prefix`
+/// and a PID.
+pub(crate) fn parse_pid_line(prefix: &[u8], data: &[u8]) -> PidLine {
+    let count = cmp::min(prefix.len(), data.len());
+    if data[..count] != prefix[..count] {
+        return PidLine::Mismatch;
+    } else if count < prefix.len() {
+        return PidLine::Incomplete;
+    }
+
+    let rest = &data[count..];
+    let digits = rest.iter().take_while(|c| c.is_ascii_digit()).count();
+    // A terminal translates the line ending.
+    let ending = match &rest[digits..] {
+        [b'\n', ..] => 1,
+        [b'\r', b'\n', ..] => 2,
+        [] | [b'\r'] if digits <= 10 => return PidLine::Incomplete,
+        _ => return PidLine::Mismatch,
+    };
+
+    match std::str::from_utf8(&rest[..digits]).unwrap().parse() {
+        Ok(pid) => PidLine::Found(count + digits + ending, pid),
+        Err(_) => PidLine::Mismatch,
+    }
+}
+
+/// Reads an output stream of the remote command and kills its
+/// process group if an `OutputLimit::Abort` limit is exceeded.
+async fn read_output<T: AsyncRead + Unpin>(
+    pipe: Option<T>,
+    limit: Option<OutputLimit>,
+    stream: OutputSource,
+    ctlpath: &str,
+    marker: &Option<Arc<PidMarker>>,
+) -> Result<(Vec<u8>, bool), SshctlError> {
+    let pipe = match pipe {
+        Some(pipe) => pipe,
+        None => return Ok((Vec::new(), false)),
+    };
+
+    let (data, truncated) = read_ssh_pipe(pipe, limit).await?;
+    if let (true, Some(OutputLimit::Abort(_))) = (truncated, limit) {
+        if let Some(pid) = marker
+            .as_ref()
+            .and_then(|marker| *marker.pid.lock().unwrap())
+        {
+            let command = signal_command("KILL", pid)?;
+            // Boxed because run waits for a child itself.
+            Box::pin(run(ctlpath, &command)).await?;
+        }
+        return Err(SshctlError::OutputLimitExceeded(stream));
+    }
+    Ok((data, truncated))
+}
@@ -0,0 +1,397 @@
+use super::{get_bytes, get_checked_u32, get_string, CommandError, MuxCmd};
+use crate::forward::ForwardAddr;
+use bytes::{Buf, BufMut, BytesMut};
+use std::convert::TryInto;
+
+// https://www.rfc-editor.org/rfc/rfc4254
+const SSH_MSG_CHANNEL_OPEN: u8 = 90;
+const SSH_MSG_CHANNEL_OPEN_CONFIRMATION: u8 = 91;
+const SSH_MSG_CHANNEL_OPEN_FAILURE: u8 = 92;
+const SSH_MSG_CHANNEL_WINDOW_ADJUST: u8 = 93;
+const SSH_MSG_CHANNEL_DATA: u8 = 94;
+const SSH_MSG_CHANNEL_EXTENDED_DATA: u8 = 95;
+const SSH_MSG_CHANNEL_EOF: u8 = 96;
+const SSH_MSG_CHANNEL_CLOSE: u8 = 97;
+const SSH_MSG_CHANNEL_REQUEST: u8 = 98;
+const SSH_MSG_CHANNEL_SUCCESS: u8 = 99;
+const SSH_MSG_CHANNEL_FAILURE: u8 = 100;
+
+pub const SSH_EXTENDED_DATA_STDERR: u32 = 1;
+
+/// SSH connection protocol message which is exchanged in mux proxy mode.
+///
+/// Each packet starts with a padding length byte which is always zero
+/// since mux proxy packets are neither padded nor encrypted.
+#[derive(Debug)]
+pub enum ChannelMsg {
+    OpenSession {
+        sender: u32,
+        window: u32,
+        max_packet: u32,
+    },
+    OpenDirect {
+        sender: u32,
+        window: u32,
+        max_packet: u32,
+        connect: ForwardAddr,
+    },
+    OpenConfirmation {
+        recipient: u32,
+        sender: u32,
+        window: u32,
+        max_packet: u32,
+    },
+    OpenFailure {
+        recipient: u32,
+        reason: u32,
+        description: String,
+    },
+    WindowAdjust {
+        recipient: u32,
+        bytes: u32,
+    },
+    Data {
+        recipient: u32,
+        data: Vec<u8>,
+    },
+    ExtendedData {
+        recipient: u32,
+        data_type: u32,
+        data: Vec<u8>,
+    },
+    Eof {
+        recipient: u32,
+    },
+    Close {
+        recipient: u32,
+    },
+    Request {
+        recipient: u32,
+        request_type: String,
+        want_reply: bool,
+        data: Vec<u8>,
+    },
+    ExitStatus {
+        recipient: u32,
+        exit_code: u32,
+    },
+    ExitSignal {
+        recipient: u32,
+        signal: String,
+    },
+    Success {
+        recipient: u32,
+    },
+    Failure {
+        recipient: u32,
+    },
+    Unknown {
+        msg_type: u8,
+    },
+}
+
+impl ChannelMsg {
+    /// Returns the local channel ID of a received message.
+    pub fn recipient(&self) -> Option<u32> {
+        match self {
+            Self::OpenConfirmation { recipient, .. }
+            | Self::OpenFailure { recipient, .. }
+            | Self::WindowAdjust { recipient, .. }
+            | Self::Data { recipient, .. }
+            | Self::ExtendedData { recipient, .. }
+            | Self::Eof { recipient }
+            | Self::Close { recipient }
+            | Self::Request { recipient, .. }
+            | Self::ExitStatus { recipient, .. }
+            | Self::ExitSignal { recipient, .. }
+            | Self::Success { recipient }
+            | Self::Failure { recipient } => Some(*recipient),
+            _ => None,
+        }
+    }
+
+    pub fn deserialize<T: Buf>(
+        buffer: &mut T,
+    ) -> Result<ChannelMsg, CommandError> {
+        if buffer.remaining() < 2 {
+            return Err(CommandError::new(
+                "At least 2 bytes are required in buffer.".into(),
+            ));
+        }
+
+        let _padding = buffer.get_u8();
+        let msg_type = buffer.get_u8();
+
+        let msg = match msg_type {
+            SSH_MSG_CHANNEL_OPEN_CONFIRMATION => Self::OpenConfirmation {
+                recipient: get_checked_u32(buffer)?,
+                sender: get_checked_u32(buffer)?,
+                window: get_checked_u32(buffer)?,
+                max_packet: get_checked_u32(buffer)?,
+            },
+            SSH_MSG_CHANNEL_OPEN_FAILURE => {
+                let msg = Self::OpenFailure {
+                    recipient: get_checked_u32(buffer)?,
+                    reason: get_checked_u32(buffer)?,
+                    description: get_string(buffer)?,
+                };
+                let _language = get_bytes(buffer)?;
+                msg
+            }
+            SSH_MSG_CHANNEL_WINDOW_ADJUST => Self::WindowAdjust {
+                recipient: get_checked_u32(buffer)?,
+                bytes: get_checked_u32(buffer)?,
+            },
+            SSH_MSG_CHANNEL_DATA => Self::Data {
+                recipient: get_checked_u32(buffer)?,
+                data: get_bytes(buffer)?,
+            },
+            SSH_MSG_CHANNEL_EXTENDED_DATA => Self::ExtendedData {
+                recipient: get_checked_u32(buffer)?,
+                data_type: get_checked_u32(buffer)?,
+                data: get_bytes(buffer)?,
+            },
+            SSH_MSG_CHANNEL_EOF => Self::Eof {
+                recipient: get_checked_u32(buffer)?,
+            },
+            SSH_MSG_CHANNEL_CLOSE => Self::Close {
+                recipient: get_checked_u32(buffer)?,
+            },
+            SSH_MSG_CHANNEL_REQUEST => Self::deserialize_request(buffer)?,
+            SSH_MSG_CHANNEL_SUCCESS => Self::Success {
+                recipient: get_checked_u32(buffer)?,
+            },
+            SSH_MSG_CHANNEL_FAILURE => Self::Failure {
+                recipient: get_checked_u32(buffer)?,
+            },
+            _ => {
+                buffer.advance(buffer.remaining());
+                Self::Unknown { msg_type }
+            }
+        };
+
+        if buffer.has_remaining() {
+            Err(CommandError::new("Garbage at end of buffer".into()))
+        } else {
+            Ok(msg)
+        }
+    }
+
+    fn deserialize_request<T: Buf>(
+        buffer: &mut T,
+    ) -> Result<ChannelMsg, CommandError> {
+        let recipient = get_checked_u32(buffer)?;
+        let request_type = get_string(buffer)?;
+        if !buffer.has_remaining() {
+            return Err(CommandError::new(
+                "Missing want reply flag in buffer.".into(),
+            ));
+        }
+        let want_reply = buffer.get_u8() != 0;
+
+        match request_type.as_str() {
+            "exit-status" => Ok(Self::ExitStatus {
+                recipient,
+                exit_code: get_checked_u32(buffer)?,
+            }),
+            "exit-signal" => {
+                let signal = get_string(buffer)?;
+                // Core dumped flag, error message and language tag.
+                buffer.advance(buffer.remaining());
+                Ok(Self::ExitSignal { recipient, signal })
+            }
+            _ => {
+                let mut data = vec![0; buffer.remaining()];
+                buffer.copy_to_slice(&mut data);
+                Ok(Self::Request {
+                    recipient,
+                    request_type,
+                    want_reply,
+                    data,
+                })
+            }
+        }
+    }
+}
+
+fn put_string(buffer: &mut BytesMut, value: &[u8]) {
+    buffer.put_u32(value.len().try_into().unwrap());
+    buffer.put_slice(value);
+}
+
+impl MuxCmd for ChannelMsg {
+    fn serialize(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(0); // padding length
+        match self {
+            Self::OpenSession {
+                sender,
+                window,
+                max_packet,
+            } => {
+                buffer.put_u8(SSH_MSG_CHANNEL_OPEN);
+                put_string(buffer, b"session");
+                buffer.put_u32(*sender);
+                buffer.put_u32(*window);
+                buffer.put_u32(*max_packet);
+            }
+            Self::OpenDirect {
+                sender,
+                window,
+                max_packet,
+                connect,
+            } => {
+                buffer.put_u8(SSH_MSG_CHANNEL_OPEN);
+                match connect {
+                    ForwardAddr::Tcp { .. } => {
+                        put_string(buffer, b"direct-tcpip")
+                    }
+                    ForwardAddr::Unix(_) => {
+                        put_string(buffer, b"direct-streamlocal@openssh.com")
+                    }
+                }
+                buffer.put_u32(*sender);
+                buffer.put_u32(*window);
+                buffer.put_u32(*max_packet);
+                put_string(buffer, connect.address());
+                match connect {
+                    ForwardAddr::Tcp { port, .. } => {
+                        buffer.put_u32((*port).into());
+                        // Originator address and port.
+                        put_string(buffer, b"127.0.0.1");
+                        buffer.put_u32(0);
+                    }
+                    ForwardAddr::Unix(_) => {
+                        // Reserved string and integer.
+                        put_string(buffer, b"");
+                        buffer.put_u32(0);
+                    }
+                }
+            }
+            Self::OpenConfirmation {
+                recipient,
+                sender,
+                window,
+                max_packet,
+            } => {
+                buffer.put_u8(SSH_MSG_CHANNEL_OPEN_CONFIRMATION);
+                buffer.put_u32(*recipient);
+                buffer.put_u32(*sender);
+                buffer.put_u32(*window);
+                buffer.put_u32(*max_packet);
+            }
+            Self::OpenFailure {
+                recipient,
+                reason,
+                description,
+            } => {
+                buffer.put_u8(SSH_MSG_CHANNEL_OPEN_FAILURE);
+                buffer.put_u32(*recipient);
+                buffer.put_u32(*reason);
+                put_string(buffer, description.as_bytes());
+                put_string(buffer, b"");
+            }
+            Self::WindowAdjust { recipient, bytes } => {
+                buffer.put_u8(SSH_MSG_CHANNEL_WINDOW_ADJUST);
+                buffer.put_u32(*recipient);
+                buffer.put_u32(*bytes);
+            }
+            Self::Data { recipient, data } => {
+                buffer.put_u8(SSH_MSG_CHANNEL_DATA);
+                buffer.put_u32(*recipient);
+                put_string(buffer, data);
+            }
+            Self::ExtendedData {
+                recipient,
+                data_type,
+                data,
+            } => {
+                buffer.put_u8(SSH_MSG_CHANNEL_EXTENDED_DATA);
+                buffer.put_u32(*recipient);
+                buffer.put_u32(*data_type);
+                put_string(buffer, data);
+            }
+            Self::Eof { recipient } => {
+                buffer.put_u8(SSH_MSG_CHANNEL_EOF);
+                buffer.put_u32(*recipient);
+            }
+            Self::Close { recipient } => {
+                buffer.put_u8(SSH_MSG_CHANNEL_CLOSE);
+                buffer.put_u32(*recipient);
+            }
+            Self::Request {
+                recipient,
+                request_type,
+                want_reply,
+                data,
+            } => {
+                buffer.put_u8(SSH_MSG_CHANNEL_REQUEST);
+                buffer.put_u32(*recipient);
+                put_string(buffer, request_type.as_bytes());
+                buffer.put_u8(*want_reply as u8);
+                buffer.put_slice(data);
+            }
+            Self::ExitStatus {
+                recipient,
+                exit_code,
+            } => {
+                buffer.put_u8(SSH_MSG_CHANNEL_REQUEST);
+                buffer.put_u32(*recipient);
+                put_string(buffer, b"exit-status");
+                buffer.put_u8(0);
+                buffer.put_u32(*exit_code);
+            }
+            Self::ExitSignal { recipient, signal } => {
+                buffer.put_u8(SSH_MSG_CHANNEL_REQUEST);
+                buffer.put_u32(*recipient);
+                put_string(buffer, b"exit-signal");
+                buffer.put_u8(0);
+                put_string(buffer, signal.as_bytes());
+                buffer.put_u8(0);
+                put_string(buffer, b"");
+                put_string(buffer, b"");
+            }
+            Self::Success { recipient } => {
+                buffer.put_u8(SSH_MSG_CHANNEL_SUCCESS);
+                buffer.put_u32(*recipient);
+            }
+            Self::Failure { recipient } => {
+                buffer.put_u8(SSH_MSG_CHANNEL_FAILURE);
+                buffer.put_u32(*recipient);
+            }
+            Self::Unknown { msg_type } => {
+                buffer.put_u8(*msg_type);
+            }
+        }
+    }
+
+    fn length(&self) -> usize {
+        2 + match self {
+            Self::OpenSession { .. } => 4 + 7 + 3 * 4,
+            Self::OpenDirect { connect, .. } => match connect {
+                ForwardAddr::Tcp { .. } => {
+                    4 + 12 + 3 * 4 + 4 + connect.address().len() + 4 + 4 + 9 + 4
+                }
+                ForwardAddr::Unix(_) => {
+                    4 + 30 + 3 * 4 + 4 + connect.address().len() + 4 + 4
+                }
+            },
+            Self::OpenConfirmation { .. } => 4 * 4,
+            Self::OpenFailure { description, .. } => {
+                2 * 4 + 4 + description.len() + 4
+            }
+            Self::WindowAdjust { .. } => 2 * 4,
+            Self::Data { data, .. } => 4 + 4 + data.len(),
+            Self::ExtendedData { data, .. } => 2 * 4 + 4 + data.len(),
+            Self::Eof { .. } | Self::Close { .. } => 4,
+            Self::Request {
+                request_type, data, ..
+            } => 4 + 4 + request_type.len() + 1 + data.len(),
+            Self::ExitStatus { .. } => 4 + 4 + 11 + 1 + 4,
+            Self::ExitSignal { signal, .. } => {
+                4 + 4 + 11 + 1 + 4 + signal.len() + 1 + 4 + 4
+            }
+            Self::Success { .. } | Self::Failure { .. } => 4,
+            Self::Unknown { .. } => 0,
+        }
+    }
+}
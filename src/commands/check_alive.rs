@@ -1,4 +1,7 @@
-use super::{CommandError, MuxCmd, MUX_ALIVE_CHECK, MUX_IS_ALIVE};
+use super::{
+    get_string, CommandError, MuxCmd, MUX_ALIVE_CHECK, MUX_FAILURE,
+    MUX_IS_ALIVE, MUX_PERMISSION_DENIED,
+};
 use bytes::{Buf, BufMut, BytesMut};
 
 #[derive(Debug)]
@@ -28,27 +31,66 @@ pub struct MuxRespCheckAlive {
     cmd: u32,
     request_id: u32,
     ssh_pid: u32,
+    reason: String,
 }
 
 impl MuxRespCheckAlive {
     pub fn is_valid(&self, request_id: u32) -> bool {
-        self.cmd == MUX_IS_ALIVE && self.request_id == request_id
+        self.request_id == request_id
+    }
+
+    pub fn cmd(&self) -> u32 {
+        self.cmd
+    }
+
+    pub fn ssh_pid(&self) -> u32 {
+        self.ssh_pid
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
     }
 
     pub fn deserialize<T: Buf>(
         buffer: &mut T,
     ) -> Result<MuxRespCheckAlive, CommandError> {
-        if buffer.remaining() != 12 {
-            return Err(CommandError::new(format!(
-                "Buffer has length {} but MuxRespCheckAlive is 12 bytes long",
-                buffer.remaining()
-            )));
+        if buffer.remaining() < 8 {
+            return Err(CommandError::new(
+                "At least 8 bytes are required in buffer.".into(),
+            ));
         }
 
-        Ok(MuxRespCheckAlive {
-            cmd: buffer.get_u32(),
-            request_id: buffer.get_u32(),
-            ssh_pid: buffer.get_u32(),
-        })
+        let cmd = buffer.get_u32();
+        let request_id = buffer.get_u32();
+
+        let (ssh_pid, reason) = match cmd {
+            MUX_IS_ALIVE => {
+                if buffer.remaining() < 4 {
+                    return Err(CommandError::new(
+                        "Received MUX_IS_ALIVE but missing pid in buffer."
+                            .into(),
+                    ));
+                }
+                (buffer.get_u32(), String::new())
+            }
+            MUX_PERMISSION_DENIED | MUX_FAILURE => (0, get_string(buffer)?),
+            _ => {
+                return Err(CommandError::new(format!(
+                    "Received invalid response: {}",
+                    cmd
+                )))
+            }
+        };
+
+        if buffer.has_remaining() {
+            Err(CommandError::new("Garbage at end of buffer".into()))
+        } else {
+            Ok(MuxRespCheckAlive {
+                cmd,
+                request_id,
+                ssh_pid,
+                reason,
+            })
+        }
     }
 }
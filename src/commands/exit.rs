@@ -1,4 +1,4 @@
-use super::{CommandError, MUX_EXIT_MESSAGE};
+use super::{CommandError, MUX_EXIT_MESSAGE, MUX_TTY_ALLOC_FAIL};
 use bytes::Buf;
 
 #[derive(Debug)]
@@ -13,6 +13,12 @@ impl MuxRespExit {
         self.cmd == MUX_EXIT_MESSAGE && self.session_id == session_id
     }
 
+    /// Returns true if the SSH master reported that no pseudo terminal
+    /// could be allocated. The session continues without a terminal.
+    pub fn is_tty_alloc_fail(&self, session_id: u32) -> bool {
+        self.cmd == MUX_TTY_ALLOC_FAIL && self.session_id == session_id
+    }
+
     pub fn exit_code(&self) -> u32 {
         self.exit_code
     }
@@ -29,18 +35,22 @@ impl MuxRespExit {
         let cmd = buffer.get_u32();
         let session_id = buffer.get_u32();
 
-        let exit_code = if cmd == MUX_EXIT_MESSAGE {
-            if buffer.remaining() < 4 {
-                return Err(CommandError::new(
-                    "Received MUX_EXIT_MESSAGE but missing exit code in buffer.".
-                    into()));
+        let exit_code = match cmd {
+            MUX_EXIT_MESSAGE => {
+                if buffer.remaining() < 4 {
+                    return Err(CommandError::new(
+                        "Received MUX_EXIT_MESSAGE but missing exit code in buffer.".
+                        into()));
+                }
+                buffer.get_u32()
+            }
+            MUX_TTY_ALLOC_FAIL => 0,
+            _ => {
+                return Err(CommandError::new(format!(
+                    "Received invalid response: {}",
+                    cmd
+                )))
             }
-            buffer.get_u32()
-        } else {
-            return Err(CommandError::new(format!(
-                "Received invalid response: {}",
-                cmd
-            )));
         };
 
         if buffer.has_remaining() {
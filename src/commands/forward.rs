@@ -0,0 +1,127 @@
+use super::{
+    get_string, CommandError, MuxCmd, MUX_FAILURE, MUX_OK,
+    MUX_PERMISSION_DENIED, MUX_REMOTE_PORT,
+};
+use crate::forward::ForwardAddr;
+use bytes::{Buf, BufMut, BytesMut};
+use std::convert::TryInto;
+
+/// Open or close forward request, depending on `cmd`.
+#[derive(Debug)]
+pub struct MuxCmdForward {
+    cmd: u32,
+    request_id: u32,
+    forward_type: u32,
+    listen: ForwardAddr,
+    connect: ForwardAddr,
+}
+
+impl MuxCmdForward {
+    pub fn new(
+        cmd: u32,
+        request_id: u32,
+        forward_type: u32,
+        listen: ForwardAddr,
+        connect: ForwardAddr,
+    ) -> Self {
+        Self {
+            cmd,
+            request_id,
+            forward_type,
+            listen,
+            connect,
+        }
+    }
+}
+
+impl MuxCmd for MuxCmdForward {
+    fn serialize(&self, buffer: &mut BytesMut) {
+        buffer.put_u32(self.cmd);
+        buffer.put_u32(self.request_id);
+        buffer.put_u32(self.forward_type);
+
+        let listen = self.listen.address();
+        buffer.put_u32(listen.len().try_into().unwrap());
+        buffer.put_slice(listen);
+        buffer.put_u32(self.listen.port());
+
+        let connect = self.connect.address();
+        buffer.put_u32(connect.len().try_into().unwrap());
+        buffer.put_slice(connect);
+        buffer.put_u32(self.connect.port());
+    }
+
+    fn length(&self) -> usize {
+        7 * 4 + self.listen.address().len() + self.connect.address().len()
+    }
+}
+
+#[derive(Debug)]
+pub struct MuxRespOpenForward {
+    cmd: u32,
+    request_id: u32,
+    remote_port: Option<u32>,
+    reason: String,
+}
+
+impl MuxRespOpenForward {
+    pub fn is_valid(&self, request_id: u32) -> bool {
+        self.request_id == request_id
+    }
+
+    pub fn cmd(&self) -> u32 {
+        self.cmd
+    }
+
+    pub fn remote_port(&self) -> Option<u32> {
+        self.remote_port
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    pub fn deserialize<T: Buf>(
+        buffer: &mut T,
+    ) -> Result<MuxRespOpenForward, CommandError> {
+        if buffer.remaining() < 8 {
+            return Err(CommandError::new(
+                "At least 8 bytes are required in buffer.".into(),
+            ));
+        }
+
+        let cmd = buffer.get_u32();
+        let request_id = buffer.get_u32();
+
+        let (remote_port, reason) = match cmd {
+            MUX_OK => (None, String::new()),
+            MUX_REMOTE_PORT => {
+                if buffer.remaining() < 4 {
+                    return Err(CommandError::new(
+                        "Received MUX_REMOTE_PORT but missing port in buffer."
+                            .into(),
+                    ));
+                }
+                (Some(buffer.get_u32()), String::new())
+            }
+            MUX_PERMISSION_DENIED | MUX_FAILURE => (None, get_string(buffer)?),
+            _ => {
+                return Err(CommandError::new(format!(
+                    "Received invalid response: {}",
+                    cmd
+                )))
+            }
+        };
+
+        if buffer.has_remaining() {
+            Err(CommandError::new("Garbage at end of buffer".into()))
+        } else {
+            Ok(MuxRespOpenForward {
+                cmd,
+                request_id,
+                remote_port,
+                reason,
+            })
+        }
+    }
+}
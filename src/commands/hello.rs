@@ -1,17 +1,38 @@
-use super::{CommandError, MuxCmd, MUX_MSG_HELLO, MUX_VERSION};
+use super::{get_string, CommandError, MuxCmd, MUX_MSG_HELLO, MUX_VERSION};
 use bytes::{Buf, BufMut, BytesMut};
+use std::collections::HashMap;
+use std::convert::TryInto;
 
 #[derive(Debug)]
-pub struct MuxCmdHello {}
+pub struct MuxCmdHello {
+    extensions: Vec<(String, String)>,
+}
+
+impl MuxCmdHello {
+    pub fn new(extensions: Vec<(String, String)>) -> Self {
+        Self { extensions }
+    }
+}
 
 impl MuxCmd for MuxCmdHello {
     fn serialize(&self, buffer: &mut BytesMut) {
         buffer.put_u32(MUX_MSG_HELLO);
         buffer.put_u32(MUX_VERSION);
+
+        for (name, value) in &self.extensions {
+            buffer.put_u32(name.len().try_into().unwrap());
+            buffer.put_slice(name.as_bytes());
+            buffer.put_u32(value.len().try_into().unwrap());
+            buffer.put_slice(value.as_bytes());
+        }
     }
 
     fn length(&self) -> usize {
-        8
+        8 + self
+            .extensions
+            .iter()
+            .map(|(name, value)| 8 + name.len() + value.len())
+            .sum::<usize>()
     }
 }
 
@@ -19,26 +40,47 @@ impl MuxCmd for MuxCmdHello {
 pub struct MuxRespHello {
     cmd: u32,
     version: u32,
+    extensions: HashMap<String, String>,
 }
 
 impl MuxRespHello {
+    /// Returns true for a hello message of any protocol version.
     pub fn is_valid(&self) -> bool {
-        self.cmd == MUX_MSG_HELLO && self.version == MUX_VERSION
+        self.cmd == MUX_MSG_HELLO
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn into_extensions(self) -> HashMap<String, String> {
+        self.extensions
     }
 
     pub fn deserialize<T: Buf>(
         buffer: &mut T,
     ) -> Result<MuxRespHello, CommandError> {
-        if buffer.remaining() != 8 {
+        if buffer.remaining() < 8 {
             return Err(CommandError::new(format!(
-                "Buffer has length {} but MuxRespHello is 8 bytes long",
+                "Buffer has length {} but MuxRespHello is at least 8 bytes long",
                 buffer.remaining()
             )));
         }
 
+        let cmd = buffer.get_u32();
+        let version = buffer.get_u32();
+
+        let mut extensions = HashMap::new();
+        while buffer.has_remaining() {
+            let name = get_string(buffer)?;
+            let value = get_string(buffer)?;
+            extensions.insert(name, value);
+        }
+
         Ok(MuxRespHello {
-            cmd: buffer.get_u32(),
-            version: buffer.get_u32(),
+            cmd,
+            version,
+            extensions,
         })
     }
 }
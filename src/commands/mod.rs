@@ -1,4 +1,4 @@
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
 use std::{error::Error, fmt};
 
 /// Low level SSH control socket protocol errors.
@@ -8,7 +8,7 @@ pub struct CommandError {
 }
 
 impl CommandError {
-    fn new(details: String) -> Self {
+    pub(crate) fn new(details: String) -> Self {
         Self { details }
     }
 }
@@ -34,6 +34,16 @@ mod exit;
 pub use exit::MuxRespExit;
 mod check_alive;
 pub use check_alive::{MuxCmdCheckAlive, MuxRespCheckAlive};
+mod status;
+pub use status::MuxRespStatus;
+mod forward;
+pub use forward::{MuxCmdForward, MuxRespOpenForward};
+mod stdio_forward;
+pub use stdio_forward::MuxCmdNewStdioForward;
+mod proxy;
+pub use proxy::MuxRespProxy;
+mod channel;
+pub use channel::{ChannelMsg, SSH_EXTENDED_DATA_STDERR};
 
 #[derive(Debug)]
 pub struct MuxCmdMessage {
@@ -46,10 +56,27 @@ pub const MUX_VERSION: u32 = 4;
 pub const MUX_MSG_HELLO: u32 = 1;
 pub const MUX_NEW_SESSION: u32 = 0x10000002;
 pub const MUX_ALIVE_CHECK: u32 = 0x10000004;
+pub const MUX_TERMINATE: u32 = 0x10000005;
+pub const MUX_OPEN_FWD: u32 = 0x10000006;
+pub const MUX_CLOSE_FWD: u32 = 0x10000007;
+pub const MUX_NEW_STDIO_FWD: u32 = 0x10000008;
+pub const MUX_STOP_LISTENING: u32 = 0x10000009;
+pub const MUX_PROXY: u32 = 0x1000000f;
 
+pub const MUX_OK: u32 = 0x80000001;
+pub const MUX_PERMISSION_DENIED: u32 = 0x80000002;
+pub const MUX_FAILURE: u32 = 0x80000003;
 pub const MUX_IS_ALIVE: u32 = 0x80000005;
 pub const MUX_SESSION_OPENED: u32 = 0x80000006;
 pub const MUX_EXIT_MESSAGE: u32 = 0x80000004;
+pub const MUX_REMOTE_PORT: u32 = 0x80000007;
+pub const MUX_TTY_ALLOC_FAIL: u32 = 0x80000008;
+pub const MUX_PROXY_ENABLED: u32 = 0x8000000f;
+
+pub const MUX_FWD_LOCAL: u32 = 1;
+pub const MUX_FWD_REMOTE: u32 = 2;
+pub const MUX_FWD_DYNAMIC: u32 = 3;
+pub const MUX_PORT_STREAMLOCAL: u32 = 0xfffffffe; // -2
 
 impl MuxCmd for MuxCmdMessage {
     fn serialize(&self, buffer: &mut BytesMut) {
@@ -61,3 +88,31 @@ impl MuxCmd for MuxCmdMessage {
         8
     }
 }
+
+fn get_checked_u32<T: Buf>(buffer: &mut T) -> Result<u32, CommandError> {
+    if buffer.remaining() < 4 {
+        return Err(CommandError::new("Missing integer in buffer.".into()));
+    }
+    Ok(buffer.get_u32())
+}
+
+fn get_bytes<T: Buf>(buffer: &mut T) -> Result<Vec<u8>, CommandError> {
+    let length = get_checked_u32(buffer)? as usize;
+    if buffer.remaining() < length {
+        return Err(CommandError::new(format!(
+            "String has length {} but only {} bytes are left in buffer",
+            length,
+            buffer.remaining()
+        )));
+    }
+
+    let mut data = vec![0; length];
+    buffer.copy_to_slice(&mut data);
+    Ok(data)
+}
+
+fn get_string<T: Buf>(buffer: &mut T) -> Result<String, CommandError> {
+    String::from_utf8(get_bytes(buffer)?).map_err(|e| {
+        CommandError::new(format!("String is not valid UTF-8: {}", e))
+    })
+}
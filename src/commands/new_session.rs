@@ -1,4 +1,7 @@
-use super::{CommandError, MuxCmd, MUX_NEW_SESSION, MUX_SESSION_OPENED};
+use super::{
+    get_string, CommandError, MuxCmd, MUX_FAILURE, MUX_NEW_SESSION,
+    MUX_PERMISSION_DENIED, MUX_SESSION_OPENED,
+};
 use bytes::{Buf, BufMut, BytesMut};
 use std::convert::TryInto;
 
@@ -13,6 +16,7 @@ pub struct MuxCmdNewSession {
     escape_char: u32,
     term: String,
     command: String,
+    env: Vec<String>,
 }
 
 impl MuxCmdNewSession {
@@ -27,8 +31,41 @@ impl MuxCmdNewSession {
             escape_char: 0xffffffff, // disabled
             term: String::new(),
             command,
+            env: Vec::new(),
         }
     }
+
+    /// Requests a pseudo terminal with the given terminal type.
+    pub fn tty(mut self, term: String) -> Self {
+        self.tty_flags = 1;
+        self.term = term;
+        self
+    }
+
+    /// Requests forwarding of the local authentication agent.
+    pub fn forward_agent(mut self, enable: bool) -> Self {
+        self.forward_agent = enable as u32;
+        self
+    }
+
+    /// Requests X11 forwarding.
+    pub fn forward_x11(mut self, enable: bool) -> Self {
+        self.forward_x11 = enable as u32;
+        self
+    }
+
+    /// Starts the command as a named subsystem, e.g. "sftp",
+    /// instead of a shell command.
+    pub fn subsystem(mut self) -> Self {
+        self.subsystem_flag = 1;
+        self
+    }
+
+    /// Sets environment variables as `NAME=value` strings.
+    pub fn env(mut self, env: Vec<String>) -> Self {
+        self.env = env;
+        self
+    }
 }
 
 impl MuxCmd for MuxCmdNewSession {
@@ -50,10 +87,19 @@ impl MuxCmd for MuxCmdNewSession {
 
         buffer.put_u32(self.command.len().try_into().unwrap());
         buffer.put_slice(self.command.as_bytes());
+
+        for var in &self.env {
+            buffer.put_u32(var.len().try_into().unwrap());
+            buffer.put_slice(var.as_bytes());
+        }
     }
 
     fn length(&self) -> usize {
-        10 * 4 + self.reserved.len() + self.term.len() + self.command.len()
+        10 * 4
+            + self.reserved.len()
+            + self.term.len()
+            + self.command.len()
+            + self.env.iter().map(|var| 4 + var.len()).sum::<usize>()
     }
 }
 
@@ -62,31 +108,67 @@ pub struct MuxRespNewSession {
     cmd: u32,
     request_id: u32,
     session_id: u32,
+    reason: String,
 }
 
 impl MuxRespNewSession {
     pub fn is_valid(&self, request_id: u32) -> bool {
-        self.cmd == MUX_SESSION_OPENED && self.request_id == request_id
+        self.request_id == request_id
+    }
+
+    pub fn cmd(&self) -> u32 {
+        self.cmd
     }
 
     pub fn session_id(&self) -> u32 {
         self.session_id
     }
 
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
     pub fn deserialize<T: Buf>(
         buffer: &mut T,
     ) -> Result<MuxRespNewSession, CommandError> {
-        if buffer.remaining() != 12 {
-            return Err(CommandError::new(format!(
-                "Buffer has length {} but MuxRespCheckAlive is 12 bytes long",
-                buffer.remaining()
-            )));
+        if buffer.remaining() < 8 {
+            return Err(CommandError::new(
+                "At least 8 bytes are required in buffer.".into(),
+            ));
         }
 
-        Ok(MuxRespNewSession {
-            cmd: buffer.get_u32(),
-            request_id: buffer.get_u32(),
-            session_id: buffer.get_u32(),
-        })
+        let cmd = buffer.get_u32();
+        let request_id = buffer.get_u32();
+
+        let (session_id, reason) = match cmd {
+            MUX_SESSION_OPENED => {
+                if buffer.remaining() < 4 {
+                    return Err(CommandError::new(
+                        "Received MUX_SESSION_OPENED but missing session id \
+                        in buffer."
+                            .into(),
+                    ));
+                }
+                (buffer.get_u32(), String::new())
+            }
+            MUX_PERMISSION_DENIED | MUX_FAILURE => (0, get_string(buffer)?),
+            _ => {
+                return Err(CommandError::new(format!(
+                    "Received invalid response: {}",
+                    cmd
+                )))
+            }
+        };
+
+        if buffer.has_remaining() {
+            Err(CommandError::new("Garbage at end of buffer".into()))
+        } else {
+            Ok(MuxRespNewSession {
+                cmd,
+                request_id,
+                session_id,
+                reason,
+            })
+        }
     }
 }
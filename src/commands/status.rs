@@ -0,0 +1,60 @@
+use super::{
+    get_string, CommandError, MUX_FAILURE, MUX_OK, MUX_PERMISSION_DENIED,
+};
+use bytes::Buf;
+
+/// Generic reply to requests which carry no other response data.
+#[derive(Debug)]
+pub struct MuxRespStatus {
+    cmd: u32,
+    request_id: u32,
+    reason: String,
+}
+
+impl MuxRespStatus {
+    pub fn is_valid(&self, request_id: u32) -> bool {
+        self.request_id == request_id
+    }
+
+    pub fn cmd(&self) -> u32 {
+        self.cmd
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    pub fn deserialize<T: Buf>(
+        buffer: &mut T,
+    ) -> Result<MuxRespStatus, CommandError> {
+        if buffer.remaining() < 8 {
+            return Err(CommandError::new(
+                "At least 8 bytes are required in buffer.".into(),
+            ));
+        }
+
+        let cmd = buffer.get_u32();
+        let request_id = buffer.get_u32();
+
+        let reason = match cmd {
+            MUX_OK => String::new(),
+            MUX_PERMISSION_DENIED | MUX_FAILURE => get_string(buffer)?,
+            _ => {
+                return Err(CommandError::new(format!(
+                    "Received invalid response: {}",
+                    cmd
+                )))
+            }
+        };
+
+        if buffer.has_remaining() {
+            Err(CommandError::new("Garbage at end of buffer".into()))
+        } else {
+            Ok(MuxRespStatus {
+                cmd,
+                request_id,
+                reason,
+            })
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use super::{MuxCmd, MUX_NEW_STDIO_FWD};
+use crate::forward::ForwardAddr;
+use bytes::{BufMut, BytesMut};
+use std::convert::TryInto;
+
+#[derive(Debug)]
+pub struct MuxCmdNewStdioForward {
+    request_id: u32,
+    reserved: String,
+    connect: ForwardAddr,
+}
+
+impl MuxCmdNewStdioForward {
+    pub fn new(request_id: u32, connect: ForwardAddr) -> Self {
+        Self {
+            request_id,
+            reserved: String::new(),
+            connect,
+        }
+    }
+}
+
+impl MuxCmd for MuxCmdNewStdioForward {
+    fn serialize(&self, buffer: &mut BytesMut) {
+        buffer.put_u32(MUX_NEW_STDIO_FWD);
+        buffer.put_u32(self.request_id);
+
+        buffer.put_u32(self.reserved.len().try_into().unwrap());
+        buffer.put_slice(self.reserved.as_bytes());
+
+        let connect = self.connect.address();
+        buffer.put_u32(connect.len().try_into().unwrap());
+        buffer.put_slice(connect);
+        buffer.put_u32(self.connect.port());
+    }
+
+    fn length(&self) -> usize {
+        5 * 4 + self.reserved.len() + self.connect.address().len()
+    }
+}
@@ -0,0 +1,427 @@
+use std::convert::TryInto;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UnixStream,
+    runtime::Handle,
+};
+use tokio_pipe::{PipeRead, PipeWrite};
+
+use crate::commands::{
+    MuxCmdForward, MuxCmdNewStdioForward, MuxRespNewSession,
+    MuxRespOpenForward, MUX_CLOSE_FWD, MUX_FWD_DYNAMIC, MUX_FWD_LOCAL,
+    MUX_FWD_REMOTE, MUX_OPEN_FWD, MUX_PORT_STREAMLOCAL,
+};
+use crate::session::{
+    check_status, hello, read_packet_response, read_status, send_fd,
+    write_command, MuxError,
+};
+use crate::SshctlError;
+
+/// Address of one end of a forwarding.
+#[derive(PartialEq, Debug, Clone, Eq)]
+pub enum ForwardAddr {
+    /// TCP host and port.
+    Tcp { host: String, port: u16 },
+    /// UNIX domain socket path.
+    Unix(PathBuf),
+}
+
+impl ForwardAddr {
+    pub(crate) fn address(&self) -> &[u8] {
+        match self {
+            Self::Tcp { host, .. } => host.as_bytes(),
+            Self::Unix(path) => path.as_os_str().as_bytes(),
+        }
+    }
+
+    pub(crate) fn port(&self) -> u32 {
+        match self {
+            Self::Tcp { port, .. } => (*port).into(),
+            Self::Unix(_) => MUX_PORT_STREAMLOCAL,
+        }
+    }
+}
+
+impl From<(&str, u16)> for ForwardAddr {
+    fn from(addr: (&str, u16)) -> Self {
+        Self::Tcp {
+            host: addr.0.into(),
+            port: addr.1,
+        }
+    }
+}
+
+impl From<&Path> for ForwardAddr {
+    fn from(path: &Path) -> Self {
+        Self::Unix(path.into())
+    }
+}
+
+impl From<PathBuf> for ForwardAddr {
+    fn from(path: PathBuf) -> Self {
+        Self::Unix(path)
+    }
+}
+
+/// A port forwarding which was established on an SSH master.
+///
+/// The forwarding is cancelled by `close`. If it is dropped without
+/// being closed, cancellation is scheduled on the current tokio runtime
+/// and failures are ignored.
+/// Use `detach` to keep the forwarding open after the guard is gone.
+///
+/// The SSH master also accepts a request for a forwarding which already
+/// exists, e.g. one from the ssh_config. The guard cannot tell it apart
+/// from a new one, so such a forwarding is cancelled as well.
+#[derive(Debug)]
+pub struct Forwarding {
+    ctlpath: String,
+    forward_type: u32,
+    listen: ForwardAddr,
+    connect: ForwardAddr,
+    allocated_port: Option<u16>,
+    closed: bool,
+}
+
+impl Forwarding {
+    /// Requests the forwarding on the SSH master and returns its guard
+    /// once the master accepted it.
+    async fn open(
+        ctlpath: &str,
+        forward_type: u32,
+        listen: ForwardAddr,
+        connect: ForwardAddr,
+    ) -> Result<Self, SshctlError> {
+        let request_id = 0;
+        let command = MuxCmdForward::new(
+            MUX_OPEN_FWD,
+            request_id,
+            forward_type,
+            listen.clone(),
+            connect.clone(),
+        );
+
+        let mut socket = UnixStream::connect(ctlpath).await?;
+        hello(&mut socket).await?;
+        let allocated_port =
+            open_forward(&mut socket, request_id, &command).await?;
+
+        Ok(Self {
+            ctlpath: ctlpath.into(),
+            forward_type,
+            listen,
+            connect,
+            allocated_port,
+            closed: false,
+        })
+    }
+
+    /// Returns the address on which the forwarding listens.
+    pub fn listen(&self) -> &ForwardAddr {
+        &self.listen
+    }
+
+    /// Returns the address to which forwarded connections are made.
+    pub fn connect(&self) -> &ForwardAddr {
+        &self.connect
+    }
+
+    /// Returns the port which was allocated by the remote host
+    /// if a remote forwarding was requested with listen port 0.
+    pub fn allocated_port(&self) -> Option<u16> {
+        self.allocated_port
+    }
+
+    /// Cancels the forwarding on the SSH master.
+    ///
+    /// This is the same as `ssh -O cancel`.
+    pub async fn close(mut self) -> Result<(), SshctlError> {
+        self.closed = true;
+        let command = self.command(MUX_CLOSE_FWD, 0);
+        close_forward(&self.ctlpath, &command).await
+    }
+
+    /// Releases the guard without cancelling the forwarding.
+    /// It stays open until the SSH master exits.
+    pub fn detach(mut self) {
+        self.closed = true;
+    }
+
+    fn command(&self, cmd: u32, request_id: u32) -> MuxCmdForward {
+        MuxCmdForward::new(
+            cmd,
+            request_id,
+            self.forward_type,
+            self.listen.clone(),
+            self.connect.clone(),
+        )
+    }
+}
+
+impl Drop for Forwarding {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+
+        // Without a runtime, the forwarding stays open. Use `close`
+        // to observe failures.
+        let handle = match Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+
+        let ctlpath = self.ctlpath.clone();
+        let command = self.command(MUX_CLOSE_FWD, 0);
+        handle.spawn(async move {
+            let _ = close_forward(&ctlpath, &command).await;
+        });
+    }
+}
+
+/// Forwards connections to the local `listen` address through an
+/// existing SSH UNIX control socket to the `connect` address,
+/// which is resolved on the remote host.
+/// Both addresses are either a `(host, port)` tuple or a UNIX socket path.
+/// An empty listen host binds to the default address of the SSH master.
+///
+/// This is the same as `ssh -O forward -L`.
+pub async fn forward_local(
+    ctlpath: &str,
+    listen: impl Into<ForwardAddr>,
+    connect: impl Into<ForwardAddr>,
+) -> Result<Forwarding, SshctlError> {
+    Forwarding::open(ctlpath, MUX_FWD_LOCAL, listen.into(), connect.into())
+        .await
+}
+
+/// Forwards connections to the `listen` address on the remote host
+/// through an existing SSH UNIX control socket to the `connect` address,
+/// which is resolved on the local host.
+/// Both addresses are either a `(host, port)` tuple or a UNIX socket path.
+/// If the listen port is 0, the remote host allocates a port which is
+/// available from `Forwarding::allocated_port`.
+///
+/// This is the same as `ssh -O forward -R`.
+pub async fn forward_remote(
+    ctlpath: &str,
+    listen: impl Into<ForwardAddr>,
+    connect: impl Into<ForwardAddr>,
+) -> Result<Forwarding, SshctlError> {
+    Forwarding::open(ctlpath, MUX_FWD_REMOTE, listen.into(), connect.into())
+        .await
+}
+
+/// Starts a SOCKS proxy on the local `listen` host and port through an
+/// existing SSH UNIX control socket. Connections through the proxy
+/// are made from the remote host.
+/// An empty listen host binds to the default address of the SSH master.
+/// The SSH master does not support UNIX socket paths for SOCKS proxies.
+///
+/// This is the same as `ssh -O forward -D`.
+pub async fn forward_dynamic(
+    ctlpath: &str,
+    listen: (&str, u16),
+) -> Result<Forwarding, SshctlError> {
+    Forwarding::open(ctlpath, MUX_FWD_DYNAMIC, listen.into(), ("", 0).into())
+        .await
+}
+
+/// A byte stream to an address which is reachable from the remote host
+/// or to a remote subsystem.
+/// It is created by `forward_stdio` or `subsystem`.
+#[derive(Debug)]
+pub struct StdioStream {
+    // Closing the control connection closes the forwarded channel.
+    _socket: UnixStream,
+    read: PipeRead,
+    write: Option<PipeWrite>,
+}
+
+impl StdioStream {
+    pub(crate) fn new(
+        socket: UnixStream,
+        read: PipeRead,
+        write: PipeWrite,
+    ) -> Self {
+        Self {
+            _socket: socket,
+            read,
+            write: Some(write),
+        }
+    }
+}
+
+impl AsyncRead for StdioStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.read).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for StdioStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.write.as_mut() {
+            Some(write) => Pin::new(write).poll_write(cx, buf),
+            None => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.write.as_mut() {
+            Some(write) => Pin::new(write).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        // Closing the pipe signals EOF to the remote side.
+        self.write = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Opens a byte stream through an existing SSH UNIX control socket to
+/// the `connect` address, which is resolved on the remote host.
+/// The address is either a `(host, port)` tuple or a UNIX socket path.
+/// No local port is bound.
+///
+/// This is the same as `ssh -W`.
+pub async fn forward_stdio(
+    ctlpath: &str,
+    connect: impl Into<ForwardAddr>,
+) -> Result<StdioStream, SshctlError> {
+    let mut socket = UnixStream::connect(ctlpath).await?;
+    hello(&mut socket).await?;
+
+    let request_id = 0;
+    let command = MuxCmdNewStdioForward::new(request_id, connect.into());
+
+    if let Err(e) = write_command(&mut socket, &command).await {
+        return Err(MuxError::new(format!(
+            "Write new stdio forward request failed: {:?}",
+            e
+        ))
+        .into());
+    }
+
+    let (remote_stdin, local_stdin) = tokio_pipe::pipe()?;
+    let (local_stdout, remote_stdout) = tokio_pipe::pipe()?;
+
+    send_fd(&socket, remote_stdin.as_raw_fd())?;
+    send_fd(&socket, remote_stdout.as_raw_fd())?;
+
+    let response = match read_packet_response(&mut socket).await {
+        Ok(x) => MuxRespNewSession::deserialize(&mut x.as_slice())?,
+        Err(e) => {
+            return Err(MuxError::new(format!(
+                "Read MuxRespNewSession failed: {:?}",
+                e
+            ))
+            .into())
+        }
+    };
+
+    if !response.is_valid(request_id) {
+        return Err(MuxError::new(format!(
+            "Received invalid new stdio forward message: {:?}",
+            response
+        ))
+        .into());
+    }
+
+    check_status(response.cmd(), request_id, response.reason())?;
+
+    Ok(StdioStream::new(socket, local_stdout, local_stdin))
+}
+
+async fn open_forward(
+    socket: &mut UnixStream,
+    request_id: u32,
+    command: &MuxCmdForward,
+) -> Result<Option<u16>, SshctlError> {
+    if let Err(e) = write_command(socket, command).await {
+        return Err(MuxError::new(format!(
+            "Write open forward request failed: {:?}",
+            e
+        ))
+        .into());
+    }
+
+    let response = match read_packet_response(socket).await {
+        Ok(x) => MuxRespOpenForward::deserialize(&mut x.as_slice())?,
+        Err(e) => {
+            return Err(MuxError::new(format!(
+                "Read MuxRespOpenForward failed: {:?}",
+                e
+            ))
+            .into())
+        }
+    };
+
+    if !response.is_valid(request_id) {
+        return Err(MuxError::new(format!(
+            "Received invalid open forward message: {:?}",
+            response
+        ))
+        .into());
+    }
+
+    check_status(response.cmd(), request_id, response.reason())?;
+    allocated_port(&response)
+}
+
+/// Returns the port which was allocated by the remote host, if any.
+pub(crate) fn allocated_port(
+    response: &MuxRespOpenForward,
+) -> Result<Option<u16>, SshctlError> {
+    match response.remote_port() {
+        Some(port) => match port.try_into() {
+            Ok(port) => Ok(Some(port)),
+            Err(_) => Err(MuxError::new(format!(
+                "Received invalid remote port: {}",
+                port
+            ))
+            .into()),
+        },
+        None => Ok(None),
+    }
+}
+
+async fn close_forward(
+    ctlpath: &str,
+    command: &MuxCmdForward,
+) -> Result<(), SshctlError> {
+    let mut socket = UnixStream::connect(ctlpath).await?;
+    hello(&mut socket).await?;
+
+    if let Err(e) = write_command(&mut socket, command).await {
+        return Err(MuxError::new(format!(
+            "Write close forward request failed: {:?}",
+            e
+        ))
+        .into());
+    }
+
+    read_status(&mut socket, 0).await
+}
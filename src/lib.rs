@@ -2,11 +2,32 @@
 
 use std::fmt;
 
+mod child;
 mod commands;
+mod forward;
+mod lines;
+mod options;
+mod proxy;
+mod remote_command;
 mod session;
 
+pub use child::{
+    spawn, ChildStderr, ChildStdin, ChildStdout, RemoteChild, Stdio,
+};
 pub use commands::CommandError;
-pub use session::{run, run_stdin, MuxError, ShellResult};
+pub use forward::{
+    forward_dynamic, forward_local, forward_remote, forward_stdio, ForwardAddr,
+    Forwarding, StdioStream,
+};
+pub use lines::{OutputLine, OutputLines};
+pub use options::{OutputLimit, OutputSource, SessionOptions, TimeoutPhase};
+pub use proxy::{ChannelEvent, ProxyChannel, ProxyConnection};
+pub use remote_command::RemoteCommand;
+pub use session::{
+    check, run, run_file, run_reader, run_sinks, run_stdin, run_with_options,
+    stop_listening, subsystem, terminate, LimitedResult, MasterInfo, MuxError,
+    ShellResult, SinkResult,
+};
 
 /// Error returned by ssh-muxcontrol library.
 #[derive(Debug)]
@@ -14,6 +35,20 @@ pub enum SshctlError {
     CommandError(CommandError),
     MuxError(MuxError),
     IoError(std::io::Error),
+    /// The SSH master refused the request with the given reason.
+    PermissionDenied {
+        request_id: u32,
+        reason: String,
+    },
+    /// The SSH master failed to process the request with the given reason.
+    Failure {
+        request_id: u32,
+        reason: String,
+    },
+    /// The given phase of a session exceeded its timeout.
+    Timeout(TimeoutPhase),
+    /// The given output stream exceeded its `OutputLimit::Abort` limit.
+    OutputLimitExceeded(OutputSource),
 }
 
 impl From<CommandError> for SshctlError {
@@ -40,6 +75,18 @@ impl fmt::Display for SshctlError {
             Self::CommandError(e) => write!(f, "CommandError: {}", e),
             Self::MuxError(e) => write!(f, "MuxError: {}", e),
             Self::IoError(e) => write!(f, "IoError: {}", e),
+            Self::PermissionDenied { request_id, reason } => write!(
+                f,
+                "PermissionDenied: request {}: {}",
+                request_id, reason
+            ),
+            Self::Failure { request_id, reason } => {
+                write!(f, "Failure: request {}: {}", request_id, reason)
+            }
+            Self::Timeout(phase) => write!(f, "Timeout: {}", phase),
+            Self::OutputLimitExceeded(stream) => {
+                write!(f, "OutputLimitExceeded: {}", stream)
+            }
         }
     }
 }
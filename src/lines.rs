@@ -0,0 +1,178 @@
+use std::collections::VecDeque;
+use std::future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::SystemTime;
+
+use futures_core::Stream;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::child::{ChildStderr, ChildStdout, RemoteChild};
+use crate::options::OutputSource;
+use crate::session::MuxError;
+use crate::SshctlError;
+
+/// A line of output of a remote command without its line ending.
+#[derive(PartialEq, Debug, Clone, Eq)]
+pub struct OutputLine {
+    /// The time when the end of the line was received.
+    pub timestamp: SystemTime,
+    pub source: OutputSource,
+    pub line: Vec<u8>,
+}
+
+/// The stdout and stderr of a remote command as lines in the order
+/// in which they were received. It is created by
+/// `RemoteChild::output_lines` and implements `Stream`.
+#[derive(Debug)]
+pub struct OutputLines {
+    stdout: Option<ChildStdout>,
+    stderr: Option<ChildStderr>,
+    stdout_partial: Vec<u8>,
+    stderr_partial: Vec<u8>,
+    pending: VecDeque<OutputLine>,
+    max_line_length: usize,
+}
+
+impl OutputLines {
+    /// Receives the next line from stdout or stderr.
+    /// An unterminated last line is returned at EOF.
+    /// Returns `None` after both streams were closed.
+    /// This is a convenience for polling the `Stream` implementation.
+    pub async fn read(&mut self) -> Option<Result<OutputLine, SshctlError>> {
+        future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl Stream for OutputLines {
+    type Item = Result<OutputLine, SshctlError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        loop {
+            if let Some(line) = this.pending.pop_front() {
+                return Poll::Ready(Some(Ok(line)));
+            }
+            if this.stdout.is_none() && this.stderr.is_none() {
+                return Poll::Ready(None);
+            }
+
+            let stdout = poll_pipe(
+                &mut this.stdout,
+                cx,
+                OutputSource::Stdout,
+                &mut this.stdout_partial,
+                this.max_line_length,
+                &mut this.pending,
+            );
+            let stderr = poll_pipe(
+                &mut this.stderr,
+                cx,
+                OutputSource::Stderr,
+                &mut this.stderr_partial,
+                this.max_line_length,
+                &mut this.pending,
+            );
+
+            match (stdout, stderr) {
+                (Poll::Ready(Err(e)), _) | (_, Poll::Ready(Err(e))) => {
+                    return Poll::Ready(Some(Err(e)))
+                }
+                (Poll::Pending, Poll::Pending) => return Poll::Pending,
+                _ => (),
+            }
+        }
+    }
+}
+
+impl RemoteChild {
+    /// Takes stdout and stderr out of the child and returns their
+    /// output as lines. Lines which are longer than `max_line_length`
+    /// bytes are split.
+    pub fn output_lines(&mut self, max_line_length: usize) -> OutputLines {
+        OutputLines {
+            stdout: self.stdout.take(),
+            stderr: self.stderr.take(),
+            stdout_partial: Vec::new(),
+            stderr_partial: Vec::new(),
+            pending: VecDeque::new(),
+            max_line_length: max_line_length.max(1),
+        }
+    }
+}
+
+/// Appends `data` to the `partial` line and moves all complete lines
+/// to `pending`.
+pub(crate) fn split_lines(
+    data: &[u8],
+    source: OutputSource,
+    partial: &mut Vec<u8>,
+    max_line_length: usize,
+    pending: &mut VecDeque<OutputLine>,
+) {
+    let timestamp = SystemTime::now();
+
+    for &byte in data {
+        if byte != b'\n' && partial.len() < max_line_length {
+            partial.push(byte);
+            continue;
+        }
+
+        pending.push_back(OutputLine {
+            timestamp,
+            source,
+            line: partial.split_off(0),
+        });
+        if byte != b'\n' {
+            partial.push(byte);
+        }
+    }
+}
+
+/// Reads once from `pipe` and splits the data into lines.
+/// A closed pipe is removed. Returns `Poll::Pending` if there is
+/// no pipe anymore.
+fn poll_pipe<T: AsyncRead + Unpin>(
+    pipe: &mut Option<T>,
+    cx: &mut Context<'_>,
+    source: OutputSource,
+    partial: &mut Vec<u8>,
+    max_line_length: usize,
+    pending: &mut VecDeque<OutputLine>,
+) -> Poll<Result<(), SshctlError>> {
+    let reader = match pipe.as_mut() {
+        Some(reader) => reader,
+        None => return Poll::Pending,
+    };
+
+    let mut data = [0; 1024];
+    let mut buf = ReadBuf::new(&mut data);
+    match ready!(Pin::new(reader).poll_read(cx, &mut buf)) {
+        Ok(()) if buf.filled().is_empty() => {
+            *pipe = None;
+            if !partial.is_empty() {
+                pending.push_back(OutputLine {
+                    timestamp: SystemTime::now(),
+                    source,
+                    line: partial.split_off(0),
+                });
+            }
+        }
+        Ok(()) => {
+            split_lines(buf.filled(), source, partial, max_line_length, pending)
+        }
+        Err(e) => {
+            *pipe = None;
+            return Poll::Ready(Err(MuxError::new(format!(
+                "Read {:?} failed: {:?}",
+                source, e
+            ))
+            .into()));
+        }
+    }
+    Poll::Ready(Ok(()))
+}
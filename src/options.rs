@@ -0,0 +1,281 @@
+use std::env;
+use std::fmt;
+use std::time::Duration;
+
+/// The phase of a session which exceeded its timeout.
+#[derive(PartialEq, Debug, Clone, Copy, Eq)]
+pub enum TimeoutPhase {
+    /// Connecting to the SSH UNIX control socket.
+    Connect,
+    /// Hello exchange and alive check with the SSH master.
+    Hello,
+    /// Opening the session on the SSH master.
+    SessionOpen,
+    /// Execution of the remote command.
+    Execution,
+}
+
+impl fmt::Display for TimeoutPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Connect => write!(f, "connect"),
+            Self::Hello => write!(f, "hello"),
+            Self::SessionOpen => write!(f, "session open"),
+            Self::Execution => write!(f, "execution"),
+        }
+    }
+}
+
+/// An output stream of a remote command.
+#[derive(PartialEq, Debug, Clone, Copy, Eq)]
+pub enum OutputSource {
+    Stdout,
+    Stderr,
+}
+
+impl fmt::Display for OutputSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Stdout => write!(f, "stdout"),
+            Self::Stderr => write!(f, "stderr"),
+        }
+    }
+}
+
+/// Limits the output of a remote command which is collected in memory
+/// to the given number of bytes.
+#[derive(PartialEq, Debug, Clone, Copy, Eq)]
+pub enum OutputLimit {
+    /// Keeps the first bytes and discards the rest.
+    /// The remote command continues to run.
+    Truncate(usize),
+    /// Keeps only the last bytes.
+    /// The remote command continues to run.
+    KeepTail(usize),
+    /// Keeps the first bytes and kills the process group
+    /// of the remote command if it writes more.
+    /// The remote command can only be killed if its PID is reported,
+    /// see `SessionOptions::report_pid`.
+    Abort(usize),
+}
+
+/// Options for new sessions on an SSH master.
+///
+/// Environment variables are passed to the SSH master which only
+/// forwards variables that match its own `SendEnv` configuration.
+/// The remote SSH server additionally filters them by `AcceptEnv`.
+///
+/// If the SSH master refuses to open the session, it fails with
+/// `SshctlError::PermissionDenied` or `SshctlError::Failure`.
+/// Agent and X11 forwarding are not confirmed by the master,
+/// so a refusal of them is not reported, see `forward_agent`.
+#[derive(PartialEq, Debug, Clone, Eq, Default)]
+pub struct SessionOptions {
+    env: Vec<(String, String)>,
+    send_env: Vec<String>,
+    term: Option<String>,
+    forward_agent: bool,
+    forward_x11: bool,
+    connect_timeout: Option<Duration>,
+    hello_timeout: Option<Duration>,
+    open_timeout: Option<Duration>,
+    exec_timeout: Option<Duration>,
+    stdout_limit: Option<OutputLimit>,
+    stderr_limit: Option<OutputLimit>,
+    // Inverted, so the PID is reported by default.
+    no_pid_report: bool,
+}
+
+impl SessionOptions {
+    /// Creates options for a plain non-interactive session.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests a pseudo terminal of the given type, e.g. `xterm`,
+    /// for the remote command. Stdout and stderr are merged by the
+    /// terminal. If the remote host cannot allocate a terminal,
+    /// the command runs without one.
+    pub fn tty(mut self, term: &str) -> Self {
+        self.term = Some(term.into());
+        self
+    }
+
+    /// Enables forwarding of the authentication agent for this session.
+    /// The SSH master silently ignores this unless its own
+    /// `ForwardAgent` option is enabled. Otherwise it requests the
+    /// forwarding from the remote host without waiting for a reply,
+    /// so a refusal by its `AllowAgentForwarding` is not reported either.
+    /// Check `SSH_AUTH_SOCK` in the remote command if the agent is
+    /// required.
+    pub fn forward_agent(mut self, enable: bool) -> Self {
+        self.forward_agent = enable;
+        self
+    }
+
+    /// Enables X11 forwarding for this session.
+    /// The SSH master silently ignores this unless its own
+    /// `ForwardX11` option is enabled and `DISPLAY` is set.
+    /// Like agent forwarding, a refusal by the remote host
+    /// is not reported.
+    pub fn forward_x11(mut self, enable: bool) -> Self {
+        self.forward_x11 = enable;
+        self
+    }
+
+    /// Sets the timeout for connecting to the SSH UNIX control socket.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the timeout for the hello exchange and alive check
+    /// with the SSH master.
+    pub fn hello_timeout(mut self, timeout: Duration) -> Self {
+        self.hello_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the timeout for opening the session on the SSH master.
+    pub fn open_timeout(mut self, timeout: Duration) -> Self {
+        self.open_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum execution time of the remote command, starting
+    /// when the session was opened. If it is exceeded, the process group
+    /// of the remote command is killed and the session is closed.
+    pub fn exec_timeout(mut self, timeout: Duration) -> Self {
+        self.exec_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits the stdout of the remote command which is collected
+    /// by `run` and similar functions.
+    pub fn stdout_limit(mut self, limit: OutputLimit) -> Self {
+        self.stdout_limit = Some(limit);
+        self
+    }
+
+    /// Limits the stderr of the remote command which is collected
+    /// by `run` and similar functions.
+    pub fn stderr_limit(mut self, limit: OutputLimit) -> Self {
+        self.stderr_limit = Some(limit);
+        self
+    }
+
+    /// Enables or disables reporting of the remote PID, which is
+    /// enabled by default. The PID is required to signal or kill the
+    /// remote command, e.g. on timeouts or when a child is dropped.
+    ///
+    /// To report it, the command is wrapped in a short script which
+    /// requires a POSIX compatible login shell on the remote host.
+    /// Disable it for csh or fish login shells and if a `ForceCommand`
+    /// expects the command unchanged in `SSH_ORIGINAL_COMMAND`.
+    pub fn report_pid(mut self, enable: bool) -> Self {
+        self.no_pid_report = !enable;
+        self
+    }
+
+    /// Sets an environment variable for the remote command.
+    pub fn env(mut self, name: &str, value: &str) -> Self {
+        self.env.push((name.into(), value.into()));
+        self
+    }
+
+    /// Forwards local environment variables whose names match the given
+    /// pattern to the remote command, like the SSH `SendEnv` option.
+    /// The pattern may contain `*` and `?` wildcards.
+    pub fn send_env(mut self, pattern: &str) -> Self {
+        self.send_env.push(pattern.into());
+        self
+    }
+
+    /// Returns the requested terminal type if a pseudo terminal
+    /// should be allocated.
+    pub(crate) fn term(&self) -> Option<&str> {
+        self.term.as_deref()
+    }
+
+    /// Returns the timeout of the given phase, if any.
+    pub(crate) fn timeout(&self, phase: TimeoutPhase) -> Option<Duration> {
+        match phase {
+            TimeoutPhase::Connect => self.connect_timeout,
+            TimeoutPhase::Hello => self.hello_timeout,
+            TimeoutPhase::SessionOpen => self.open_timeout,
+            TimeoutPhase::Execution => self.exec_timeout,
+        }
+    }
+
+    /// Returns the limits of stdout and stderr.
+    pub(crate) fn output_limits(&self) -> [Option<OutputLimit>; 2] {
+        [self.stdout_limit, self.stderr_limit]
+    }
+
+    pub(crate) fn pid_reporting(&self) -> bool {
+        !self.no_pid_report
+    }
+
+    pub(crate) fn agent_forwarding(&self) -> bool {
+        self.forward_agent
+    }
+
+    pub(crate) fn x11_forwarding(&self) -> bool {
+        self.forward_x11
+    }
+
+    /// Returns the environment as `NAME=value` strings.
+    /// Explicitly set variables are sent last and take precedence.
+    pub(crate) fn env_strings(&self) -> Vec<String> {
+        let mut env_strings = Vec::new();
+
+        if !self.send_env.is_empty() {
+            for (name, value) in env::vars_os() {
+                if let (Some(name), Some(value)) =
+                    (name.to_str(), value.to_str())
+                {
+                    if self
+                        .send_env
+                        .iter()
+                        .any(|pattern| match_pattern(name, pattern))
+                    {
+                        env_strings.push(format!("{}={}", name, value));
+                    }
+                }
+            }
+        }
+
+        for (name, value) in &self.env {
+            env_strings.push(format!("{}={}", name, value));
+        }
+
+        env_strings
+    }
+}
+
+/// Matches a name against a pattern with `*` and `?` wildcards.
+pub(crate) fn match_pattern(name: &str, pattern: &str) -> bool {
+    let name = name.as_bytes();
+    let pattern = pattern.as_bytes();
+
+    let (mut n, mut p) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == name[n]) {
+            n += 1;
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            n = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
@@ -0,0 +1,639 @@
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixStream,
+    },
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender, WeakUnboundedSender},
+        Notify,
+    },
+};
+
+use crate::commands::{
+    ChannelMsg, MuxCmd, MuxCmdMessage, MuxRespProxy, MUX_PROXY,
+    SSH_EXTENDED_DATA_STDERR,
+};
+use crate::forward::ForwardAddr;
+use crate::session::{
+    check_status, hello_extensions, read_packet_response, write_command,
+    MuxError, ShellResult,
+};
+use crate::SshctlError;
+
+const WINDOW_SIZE: u32 = 2 * 1024 * 1024;
+const MAX_PACKET_SIZE: u32 = 32 * 1024;
+
+/// Data or status which was received on a `ProxyChannel`.
+#[derive(PartialEq, Debug, Clone, Eq)]
+pub enum ChannelEvent {
+    /// Channel data, e.g. the stdout of a remote command.
+    Data(Vec<u8>),
+    /// Extended channel data, e.g. the stderr of a remote command.
+    ExtendedData(Vec<u8>),
+    /// The remote side will not send any more data.
+    Eof,
+    /// The remote command exited with the given exit code.
+    ExitStatus(u32),
+    /// The remote command was terminated by the given signal.
+    ExitSignal(String),
+}
+
+struct ChannelState {
+    remote_id: AtomicU32,
+    max_packet: AtomicU32,
+    send_window: Mutex<u32>,
+    confirmed: AtomicBool,
+    dropped: AtomicBool,
+    closed: AtomicBool,
+    close_sent: AtomicBool,
+    // Why the proxy connection failed, if it did not close cleanly.
+    error: Mutex<Option<String>>,
+    notify: Notify,
+}
+
+struct ChannelEntry {
+    events: UnboundedSender<ChannelMsg>,
+    state: Arc<ChannelState>,
+}
+
+#[derive(Default)]
+struct Channels {
+    entries: HashMap<u32, ChannelEntry>,
+    next_id: u32,
+}
+
+/// A control socket connection in mux proxy mode.
+///
+/// In proxy mode, the SSH master forwards SSH connection protocol
+/// messages, so many channels can share a single control connection.
+/// This requires OpenSSH 7.4 or later.
+///
+/// The connection is closed when the handle and all of its channels
+/// are dropped.
+pub struct ProxyConnection {
+    channels: Arc<Mutex<Channels>>,
+    writer: UnboundedSender<Bytes>,
+    extensions: HashMap<String, String>,
+}
+
+impl ProxyConnection {
+    /// Connects to an existing SSH UNIX control socket and switches
+    /// the connection to proxy mode.
+    pub async fn connect(ctlpath: &str) -> Result<Self, SshctlError> {
+        Self::connect_with_extensions(ctlpath, Vec::new()).await
+    }
+
+    /// Same as `connect` but announces the given hello extensions
+    /// to the SSH master. Unknown extensions are ignored by OpenSSH.
+    pub async fn connect_with_extensions(
+        ctlpath: &str,
+        extensions: Vec<(String, String)>,
+    ) -> Result<Self, SshctlError> {
+        let mut socket = UnixStream::connect(ctlpath).await?;
+
+        let master_extensions =
+            hello_extensions(&mut socket, extensions).await?;
+
+        let request_id = 0;
+        let command = MuxCmdMessage {
+            request: MUX_PROXY,
+            param: request_id,
+        };
+
+        if let Err(e) = write_command(&mut socket, &command).await {
+            return Err(MuxError::new(format!(
+                "Write proxy request failed: {:?}",
+                e
+            ))
+            .into());
+        }
+
+        let response = match read_packet_response(&mut socket).await {
+            Ok(x) => MuxRespProxy::deserialize(&mut x.as_slice())?,
+            Err(e) => {
+                return Err(MuxError::new(format!(
+                    "Read MuxRespProxy failed: {:?}",
+                    e
+                ))
+                .into())
+            }
+        };
+
+        if !response.is_valid(request_id) {
+            return Err(MuxError::new(format!(
+                "Received invalid proxy message: {:?}",
+                response
+            ))
+            .into());
+        }
+
+        check_status(response.cmd(), request_id, response.reason())?;
+
+        let (read, write) = socket.into_split();
+        let channels = Arc::new(Mutex::new(Channels::default()));
+        let (writer, packets) = mpsc::unbounded_channel();
+
+        tokio::spawn(write_packets(write, packets));
+        tokio::spawn(read_packets(read, channels.clone(), writer.downgrade()));
+
+        Ok(Self {
+            channels,
+            writer,
+            extensions: master_extensions,
+        })
+    }
+
+    /// Returns the hello extensions which were announced
+    /// by the SSH master.
+    pub fn extensions(&self) -> &HashMap<String, String> {
+        &self.extensions
+    }
+
+    /// Opens a new session channel.
+    /// A command or subsystem can be started on it afterwards.
+    pub async fn open_session(&self) -> Result<ProxyChannel, SshctlError> {
+        self.open(|sender| ChannelMsg::OpenSession {
+            sender,
+            window: WINDOW_SIZE,
+            max_packet: MAX_PACKET_SIZE,
+        })
+        .await
+    }
+
+    /// Opens a new session channel and runs the given shell command
+    /// on the remote hosts default shell.
+    pub async fn exec(
+        &self,
+        command: &str,
+    ) -> Result<ProxyChannel, SshctlError> {
+        let mut channel = self.open_session().await?;
+        channel.exec(command).await?;
+        Ok(channel)
+    }
+
+    /// Opens a new session channel and starts the given subsystem,
+    /// e.g. "sftp".
+    pub async fn subsystem(
+        &self,
+        name: &str,
+    ) -> Result<ProxyChannel, SshctlError> {
+        let mut channel = self.open_session().await?;
+        channel.subsystem(name).await?;
+        Ok(channel)
+    }
+
+    /// Opens a channel to the `connect` address, which is resolved
+    /// on the remote host. The address is either a `(host, port)` tuple
+    /// or a UNIX socket path.
+    pub async fn direct(
+        &self,
+        connect: impl Into<ForwardAddr>,
+    ) -> Result<ProxyChannel, SshctlError> {
+        let connect = connect.into();
+        self.open(|sender| ChannelMsg::OpenDirect {
+            sender,
+            window: WINDOW_SIZE,
+            max_packet: MAX_PACKET_SIZE,
+            connect,
+        })
+        .await
+    }
+
+    /// Runs a given shell command on the remote hosts default shell
+    /// in a new channel and collects its output.
+    /// The exit code is 255 if the remote command did not report one,
+    /// e.g. because it was killed by a signal.
+    pub async fn run(&self, command: &str) -> Result<ShellResult, SshctlError> {
+        let mut channel = self.exec(command).await?;
+        channel.eof()?;
+
+        let mut result = ShellResult {
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            exit_code: 255,
+        };
+
+        while let Some(event) = channel.read().await? {
+            match event {
+                ChannelEvent::Data(mut data) => result.stdout.append(&mut data),
+                ChannelEvent::ExtendedData(mut data) => {
+                    result.stderr.append(&mut data)
+                }
+                ChannelEvent::ExitStatus(exit_code) => {
+                    result.exit_code = exit_code
+                }
+                _ => (),
+            }
+        }
+
+        Ok(result)
+    }
+
+    async fn open<F: FnOnce(u32) -> ChannelMsg>(
+        &self,
+        open_msg: F,
+    ) -> Result<ProxyChannel, SshctlError> {
+        let state = Arc::new(ChannelState {
+            remote_id: AtomicU32::new(0),
+            max_packet: AtomicU32::new(0),
+            send_window: Mutex::new(0),
+            confirmed: AtomicBool::new(false),
+            dropped: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            close_sent: AtomicBool::new(false),
+            error: Mutex::new(None),
+            notify: Notify::new(),
+        });
+        let (events_tx, events) = mpsc::unbounded_channel();
+
+        let local_id = {
+            let mut channels = self.channels.lock().unwrap();
+            let local_id = channels.next_id;
+            channels.next_id = channels.next_id.wrapping_add(1);
+            channels.entries.insert(
+                local_id,
+                ChannelEntry {
+                    events: events_tx,
+                    state: state.clone(),
+                },
+            );
+            local_id
+        };
+
+        let mut channel = ProxyChannel {
+            channels: self.channels.clone(),
+            state,
+            events,
+            pending: VecDeque::new(),
+            writer: self.writer.clone(),
+            consumed: 0,
+        };
+
+        send_msg(&self.writer, &open_msg(local_id))?;
+
+        match channel.events.recv().await {
+            Some(ChannelMsg::OpenConfirmation { .. }) => Ok(channel),
+            Some(ChannelMsg::OpenFailure {
+                reason,
+                description,
+                ..
+            }) => Err(MuxError::new(format!(
+                "Open channel failed: {} ({})",
+                description, reason
+            ))
+            .into()),
+            Some(msg) => Err(MuxError::new(format!(
+                "Received invalid open channel response: {:?}",
+                msg
+            ))
+            .into()),
+            None => Err(channel.closed_error("Proxy connection closed")),
+        }
+    }
+}
+
+/// A channel which was opened on a `ProxyConnection`.
+///
+/// The channel is closed when it is dropped.
+pub struct ProxyChannel {
+    channels: Arc<Mutex<Channels>>,
+    state: Arc<ChannelState>,
+    events: UnboundedReceiver<ChannelMsg>,
+    pending: VecDeque<ChannelMsg>,
+    writer: UnboundedSender<Bytes>,
+    consumed: u32,
+}
+
+impl ProxyChannel {
+    /// Runs the given shell command on the remote hosts default shell.
+    pub async fn exec(&mut self, command: &str) -> Result<(), SshctlError> {
+        self.request("exec", &encode_string(command.as_bytes()))
+            .await
+    }
+
+    /// Starts the given subsystem, e.g. "sftp".
+    pub async fn subsystem(&mut self, name: &str) -> Result<(), SshctlError> {
+        self.request("subsystem", &encode_string(name.as_bytes()))
+            .await
+    }
+
+    /// Sends a channel request and waits for its reply.
+    /// The request specific `data` must already be encoded
+    /// in SSH wire format.
+    pub async fn request(
+        &mut self,
+        request_type: &str,
+        data: &[u8],
+    ) -> Result<(), SshctlError> {
+        send_msg(
+            &self.writer,
+            &ChannelMsg::Request {
+                recipient: self.remote_id(),
+                request_type: request_type.into(),
+                want_reply: true,
+                data: data.to_vec(),
+            },
+        )?;
+
+        loop {
+            match self.events.recv().await {
+                Some(ChannelMsg::Success { .. }) => return Ok(()),
+                Some(ChannelMsg::Failure { .. }) => {
+                    return Err(MuxError::new(format!(
+                        "Channel request {} failed",
+                        request_type
+                    ))
+                    .into())
+                }
+                Some(msg) => self.pending.push_back(msg),
+                None => return Err(self.closed_error("Channel closed")),
+            }
+        }
+    }
+
+    /// Receives the next event from the channel.
+    /// Returns `None` after the channel was closed and an error
+    /// if the proxy connection failed.
+    pub async fn read(&mut self) -> Result<Option<ChannelEvent>, SshctlError> {
+        loop {
+            let msg = match self.pending.pop_front() {
+                Some(msg) => msg,
+                None => match self.events.recv().await {
+                    Some(msg) => msg,
+                    None => match self.state.error.lock().unwrap().clone() {
+                        Some(e) => return Err(MuxError::new(e).into()),
+                        None => return Ok(None),
+                    },
+                },
+            };
+
+            match msg {
+                ChannelMsg::Data { data, .. } => {
+                    self.consume(data.len());
+                    return Ok(Some(ChannelEvent::Data(data)));
+                }
+                ChannelMsg::ExtendedData {
+                    data_type, data, ..
+                } => {
+                    self.consume(data.len());
+                    if data_type == SSH_EXTENDED_DATA_STDERR {
+                        return Ok(Some(ChannelEvent::ExtendedData(data)));
+                    }
+                }
+                ChannelMsg::Eof { .. } => return Ok(Some(ChannelEvent::Eof)),
+                ChannelMsg::ExitStatus { exit_code, .. } => {
+                    return Ok(Some(ChannelEvent::ExitStatus(exit_code)))
+                }
+                ChannelMsg::ExitSignal { signal, .. } => {
+                    return Ok(Some(ChannelEvent::ExitSignal(signal)))
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Sends data to the channel, e.g. to the stdin of a remote command.
+    /// Waits until the remote side is ready to receive it.
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<(), SshctlError> {
+        while !data.is_empty() {
+            let count = self.reserve_window(data.len()).await?;
+            send_msg(
+                &self.writer,
+                &ChannelMsg::Data {
+                    recipient: self.remote_id(),
+                    data: data[..count].to_vec(),
+                },
+            )?;
+            data = &data[count..];
+        }
+        Ok(())
+    }
+
+    /// Signals the remote side that no more data will be sent.
+    pub fn eof(&mut self) -> Result<(), SshctlError> {
+        send_msg(
+            &self.writer,
+            &ChannelMsg::Eof {
+                recipient: self.remote_id(),
+            },
+        )
+    }
+
+    fn closed_error(&self, message: &str) -> SshctlError {
+        match self.state.error.lock().unwrap().clone() {
+            Some(e) => MuxError::new(e).into(),
+            None => MuxError::new(message.into()).into(),
+        }
+    }
+
+    fn remote_id(&self) -> u32 {
+        self.state.remote_id.load(Ordering::SeqCst)
+    }
+
+    fn consume(&mut self, count: usize) {
+        self.consumed += count as u32;
+        if self.consumed >= WINDOW_SIZE / 2 {
+            let _ = send_msg(
+                &self.writer,
+                &ChannelMsg::WindowAdjust {
+                    recipient: self.remote_id(),
+                    bytes: self.consumed,
+                },
+            );
+            self.consumed = 0;
+        }
+    }
+
+    async fn reserve_window(
+        &self,
+        wanted: usize,
+    ) -> Result<usize, SshctlError> {
+        loop {
+            let notified = self.state.notify.notified();
+            if self.state.closed.load(Ordering::SeqCst) {
+                return Err(self.closed_error("Channel closed"));
+            }
+
+            {
+                let mut window = self.state.send_window.lock().unwrap();
+                if *window > 0 {
+                    let max_packet =
+                        self.state.max_packet.load(Ordering::SeqCst);
+                    let count = cmp::min(*window, max_packet) as usize;
+                    let count = cmp::min(count, wanted);
+                    *window -= count as u32;
+                    return Ok(count);
+                }
+            }
+
+            notified.await;
+        }
+    }
+}
+
+impl Drop for ProxyChannel {
+    fn drop(&mut self) {
+        // Synchronizes with the open confirmation in read_packets.
+        let _channels = self.channels.lock().unwrap();
+
+        if !self.state.confirmed.load(Ordering::SeqCst) {
+            self.state.dropped.store(true, Ordering::SeqCst);
+        } else if !self.state.closed.load(Ordering::SeqCst)
+            && !self.state.close_sent.swap(true, Ordering::SeqCst)
+        {
+            let _ = send_msg(
+                &self.writer,
+                &ChannelMsg::Close {
+                    recipient: self.remote_id(),
+                },
+            );
+        }
+    }
+}
+
+fn encode_string(value: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(4 + value.len());
+    data.put_u32(value.len().try_into().unwrap());
+    data.put_slice(value);
+    data
+}
+
+fn send_msg(
+    writer: &UnboundedSender<Bytes>,
+    msg: &ChannelMsg,
+) -> Result<(), SshctlError> {
+    let mut buffer = BytesMut::with_capacity(msg.length() + 4);
+    buffer.put_u32(msg.length().try_into().unwrap());
+    msg.serialize(&mut buffer);
+
+    if writer.send(buffer.freeze()).is_err() {
+        return Err(MuxError::new("Proxy connection closed".into()).into());
+    }
+    Ok(())
+}
+
+async fn write_packets(
+    mut socket: OwnedWriteHalf,
+    mut packets: UnboundedReceiver<Bytes>,
+) {
+    while let Some(packet) = packets.recv().await {
+        if socket.write_all(&packet).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn read_packets(
+    mut socket: OwnedReadHalf,
+    channels: Arc<Mutex<Channels>>,
+    writer: WeakUnboundedSender<Bytes>,
+) {
+    let error = loop {
+        let packet = match read_packet_response(&mut socket).await {
+            Ok(packet) => packet,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break None,
+            Err(e) => break Some(format!("Read proxy packet failed: {:?}", e)),
+        };
+        let msg = match ChannelMsg::deserialize(&mut packet.as_slice()) {
+            Ok(msg) => msg,
+            Err(e) => {
+                break Some(format!("Received invalid proxy packet: {}", e))
+            }
+        };
+
+        let recipient = match msg.recipient() {
+            Some(recipient) => recipient,
+            None => continue,
+        };
+
+        let mut channels = channels.lock().unwrap();
+        let entry = match channels.entries.get(&recipient) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let state = entry.state.clone();
+        let remote_id = state.remote_id.load(Ordering::SeqCst);
+
+        match &msg {
+            ChannelMsg::OpenConfirmation {
+                sender,
+                window,
+                max_packet,
+                ..
+            } => {
+                state.remote_id.store(*sender, Ordering::SeqCst);
+                state.max_packet.store(*max_packet, Ordering::SeqCst);
+                *state.send_window.lock().unwrap() = *window;
+                state.confirmed.store(true, Ordering::SeqCst);
+
+                // The channel handle was dropped before it was confirmed.
+                if state.dropped.load(Ordering::SeqCst) {
+                    state.close_sent.store(true, Ordering::SeqCst);
+                    if let Some(writer) = writer.upgrade() {
+                        let reply = ChannelMsg::Close { recipient: *sender };
+                        let _ = send_msg(&writer, &reply);
+                    }
+                    continue;
+                }
+            }
+            ChannelMsg::OpenFailure { .. } => {
+                state.closed.store(true, Ordering::SeqCst);
+                if let Some(entry) = channels.entries.remove(&recipient) {
+                    let _ = entry.events.send(msg);
+                }
+                continue;
+            }
+            ChannelMsg::WindowAdjust { bytes, .. } => {
+                {
+                    let mut window = state.send_window.lock().unwrap();
+                    *window = window.saturating_add(*bytes);
+                }
+                state.notify.notify_waiters();
+                continue;
+            }
+            ChannelMsg::Request { want_reply, .. } => {
+                if *want_reply {
+                    if let Some(writer) = writer.upgrade() {
+                        let reply = ChannelMsg::Failure {
+                            recipient: remote_id,
+                        };
+                        let _ = send_msg(&writer, &reply);
+                    }
+                }
+                continue;
+            }
+            ChannelMsg::Close { .. } => {
+                if !state.close_sent.swap(true, Ordering::SeqCst) {
+                    if let Some(writer) = writer.upgrade() {
+                        let reply = ChannelMsg::Close {
+                            recipient: remote_id,
+                        };
+                        let _ = send_msg(&writer, &reply);
+                    }
+                }
+                state.closed.store(true, Ordering::SeqCst);
+                state.notify.notify_waiters();
+                channels.entries.remove(&recipient);
+                continue;
+            }
+            _ => (),
+        }
+
+        let _ = entry.events.send(msg);
+    };
+
+    for (_, entry) in channels.lock().unwrap().entries.drain() {
+        *entry.state.error.lock().unwrap() = error.clone();
+        entry.state.closed.store(true, Ordering::SeqCst);
+        entry.state.notify.notify_waiters();
+    }
+}
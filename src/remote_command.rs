@@ -0,0 +1,160 @@
+use crate::child::{spawn_stdio, RemoteChild, Stdio};
+use crate::options::SessionOptions;
+use crate::session::{LimitedResult, MuxError};
+use crate::SshctlError;
+
+/// A builder for remote commands, similar to `std::process::Command`.
+///
+/// The program and its arguments are quoted for the remote shell,
+/// so they are passed verbatim to the remote program.
+#[derive(PartialEq, Debug, Clone, Eq)]
+pub struct RemoteCommand {
+    program: String,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    current_dir: Option<String>,
+    options: SessionOptions,
+    stdin: Stdio,
+    stdout: Stdio,
+    stderr: Stdio,
+}
+
+impl RemoteCommand {
+    /// Creates a command which executes the given program.
+    pub fn new(program: &str) -> Self {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+            env: Vec::new(),
+            current_dir: None,
+            options: SessionOptions::new(),
+            stdin: Stdio::piped(),
+            stdout: Stdio::piped(),
+            stderr: Stdio::piped(),
+        }
+    }
+
+    /// Adds an argument to the program.
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Adds multiple arguments to the program.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.args
+            .extend(args.into_iter().map(|arg| arg.as_ref().into()));
+        self
+    }
+
+    /// Sets an environment variable for the program.
+    /// Unlike `SessionOptions::env`, the variable is set by the remote
+    /// shell and is not filtered by `SendEnv` or `AcceptEnv`.
+    pub fn env(mut self, name: &str, value: &str) -> Self {
+        self.env.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sets the working directory of the program on the remote host.
+    pub fn current_dir(mut self, dir: &str) -> Self {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets the options for the session in which the program runs.
+    pub fn options(mut self, options: SessionOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Configures the stdin of the program.
+    pub fn stdin(mut self, stdio: Stdio) -> Self {
+        self.stdin = stdio;
+        self
+    }
+
+    /// Configures the stdout of the program.
+    pub fn stdout(mut self, stdio: Stdio) -> Self {
+        self.stdout = stdio;
+        self
+    }
+
+    /// Configures the stderr of the program.
+    pub fn stderr(mut self, stdio: Stdio) -> Self {
+        self.stderr = stdio;
+        self
+    }
+
+    /// Returns the shell command line which is executed
+    /// on the remote host.
+    pub fn command_line(&self) -> Result<String, SshctlError> {
+        let mut command_line = String::new();
+
+        if let Some(dir) = &self.current_dir {
+            command_line.push_str(&format!("cd {} && ", quote(dir)));
+        }
+
+        for (name, value) in &self.env {
+            if !is_valid_name(name) {
+                return Err(MuxError::new(format!(
+                    "Invalid environment variable name: {:?}",
+                    name
+                ))
+                .into());
+            }
+            command_line.push_str(&format!("{}={} ", name, quote(value)));
+        }
+
+        command_line.push_str(&quote(&self.program));
+        for arg in &self.args {
+            command_line.push(' ');
+            command_line.push_str(&quote(arg));
+        }
+
+        Ok(command_line)
+    }
+
+    /// Starts the command though an existing SSH UNIX control socket.
+    /// The configured stdio streams are available from the returned child.
+    pub async fn spawn(
+        &self,
+        ctlpath: &str,
+    ) -> Result<RemoteChild, SshctlError> {
+        spawn_stdio(
+            ctlpath,
+            &self.command_line()?,
+            &self.options,
+            &[self.stdin, self.stdout, self.stderr],
+        )
+        .await
+    }
+
+    /// Runs the command though an existing SSH UNIX control socket
+    /// and collects its output. Streams which are not piped
+    /// are empty in the result.
+    pub async fn output(
+        &self,
+        ctlpath: &str,
+    ) -> Result<LimitedResult, SshctlError> {
+        self.spawn(ctlpath).await?.wait_with_output().await
+    }
+}
+
+/// Quotes a string for a POSIX shell.
+pub(crate) fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
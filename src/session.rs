@@ -1,20 +1,29 @@
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::error::Error;
 use std::fmt;
-use std::os::unix::io::AsRawFd;
+use std::fs::OpenOptions;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
 
 use bytes::{BufMut, BytesMut};
 use sendfd::SendWithFd;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    fs::File,
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::UnixStream,
 };
 use tokio_pipe::{PipeRead, PipeWrite};
 
+use crate::child::{spawn, until, Stdio};
 use crate::commands::{
-    MuxCmd, MuxCmdCheckAlive, MuxCmdHello, MuxCmdNewSession, MuxRespCheckAlive,
-    MuxRespExit, MuxRespHello, MuxRespNewSession,
+    MuxCmd, MuxCmdCheckAlive, MuxCmdHello, MuxCmdMessage, MuxCmdNewSession,
+    MuxRespCheckAlive, MuxRespExit, MuxRespHello, MuxRespNewSession,
+    MuxRespStatus, MUX_FAILURE, MUX_PERMISSION_DENIED, MUX_STOP_LISTENING,
+    MUX_TERMINATE, MUX_VERSION,
 };
+use crate::forward::StdioStream;
+use crate::options::{OutputLimit, SessionOptions};
 use crate::SshctlError;
 
 /// A simple struct which contains the stdout, stderr and exit code
@@ -26,6 +35,51 @@ pub struct ShellResult {
     pub exit_code: u32,
 }
 
+/// Contains the stdout, stderr and exit code of a completed remote
+/// command whose output was collected within the `OutputLimit`s
+/// of its session.
+#[derive(PartialEq, Debug, Clone, Eq)]
+pub struct LimitedResult {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit_code: u32,
+    /// Stdout was cut by an `OutputLimit`.
+    pub stdout_truncated: bool,
+    /// Stderr was cut by an `OutputLimit`.
+    pub stderr_truncated: bool,
+}
+
+impl From<LimitedResult> for ShellResult {
+    fn from(result: LimitedResult) -> Self {
+        Self {
+            stdout: result.stdout,
+            stderr: result.stderr,
+            exit_code: result.exit_code,
+        }
+    }
+}
+
+/// Contains the number of bytes which a completed remote command wrote
+/// to its stdout and stderr sinks and its exit code.
+#[derive(PartialEq, Debug, Clone, Copy, Eq)]
+pub struct SinkResult {
+    pub stdout_len: u64,
+    pub stderr_len: u64,
+    pub exit_code: u32,
+}
+
+/// Information about a running SSH master process.
+#[derive(PartialEq, Debug, Clone, Eq)]
+pub struct MasterInfo {
+    /// Process ID of the SSH master.
+    pub pid: u32,
+    /// Version of the multiplexing protocol which the master announced.
+    /// This crate supports version 4.
+    pub version: u32,
+    /// Hello extensions which were announced by the master.
+    pub extensions: HashMap<String, String>,
+}
+
 /// SSH control socket errors.
 #[derive(Debug)]
 pub struct MuxError {
@@ -33,7 +87,7 @@ pub struct MuxError {
 }
 
 impl MuxError {
-    fn new(details: String) -> Self {
+    pub(crate) fn new(details: String) -> Self {
         Self { details }
     }
 }
@@ -50,6 +104,10 @@ impl Error for MuxError {}
 /// though an existing SSH UNIX control socket.
 /// The SSH control socket is created
 /// outside of this crate by an existing SSH connection.
+///
+/// The command is wrapped to report its PID, which requires a POSIX
+/// compatible login shell on the remote host. Use `run_with_options`
+/// with `SessionOptions::report_pid(false)` to send it unchanged.
 pub async fn run(
     path: &str,
     command: &str,
@@ -69,35 +127,254 @@ pub async fn run_stdin(
     command: &str,
     stdin: Option<Vec<u8>>,
 ) -> Result<ShellResult, SshctlError> {
+    run_with_options(ctlpath, command, stdin, &SessionOptions::new())
+        .await
+        .map(ShellResult::from)
+}
+
+/// Runs a given shell command on the remote hosts default shell
+/// though an existing SSH UNIX control socket.
+/// The SSH control socket is created
+/// outside of this crate by an existing SSH connection.
+///
+/// This function is the same as `run_stdin` but the session is
+/// configured by the given options. The result reports whether
+/// the output was cut by the output limits of the options.
+pub async fn run_with_options(
+    ctlpath: &str,
+    command: &str,
+    stdin: Option<Vec<u8>>,
+    options: &SessionOptions,
+) -> Result<LimitedResult, SshctlError> {
+    let stdin_data = stdin.unwrap_or_default();
+    run_reader(ctlpath, command, &stdin_data[..], options).await
+}
+
+/// Runs a given shell command on the remote hosts default shell
+/// though an existing SSH UNIX control socket.
+/// The SSH control socket is created
+/// outside of this crate by an existing SSH connection.
+///
+/// This function is the same as `run_with_options` but the remote
+/// commands STDIN is read from the given reader while the command runs.
+/// The reader is only read as fast as the remote command consumes
+/// the data.
+pub async fn run_reader<R: AsyncRead + Unpin>(
+    ctlpath: &str,
+    command: &str,
+    stdin: R,
+    options: &SessionOptions,
+) -> Result<LimitedResult, SshctlError> {
     //#[cfg(debug_assertions)]
     //eprintln!("Mux::request_session: {}", ctlpath);
-    let mut socket = UnixStream::connect(ctlpath).await?;
+    let mut child = spawn(ctlpath, command, options).await?;
+    let local_stdin = child.stdin.take();
+
+    // The execution timeout also applies to writing stdin.
+    let result = until(child.deadline(), async {
+        tokio::join! {
+            write_stdin(local_stdin, stdin),
+            child.collect_output(),
+        }
+    })
+    .await;
 
-    hello(&mut socket).await?;
-    let request_id = check_mux_alive(&mut socket, 0).await?;
-    let (session_id, local_stdin, local_stdout, local_stderr) =
-        new_session(&mut socket, request_id, command).await?;
+    match result {
+        Some((tx_stdin, output)) => {
+            tx_stdin?;
+            output
+        }
+        None => Err(child.timed_out().await),
+    }
+}
 
-    let stdin_data = stdin.unwrap_or_default();
+/// Runs a given shell command on the remote hosts default shell
+/// though an existing SSH UNIX control socket.
+/// The SSH control socket is created
+/// outside of this crate by an existing SSH connection.
+///
+/// This function is the same as `run_reader` but the remote commands
+/// STDIN is read from the given local file.
+pub async fn run_file(
+    ctlpath: &str,
+    command: &str,
+    path: impl AsRef<Path>,
+    options: &SessionOptions,
+) -> Result<LimitedResult, SshctlError> {
+    let file = File::open(path).await?;
+    run_reader(ctlpath, command, file, options).await
+}
 
-    let (rx_rc, tx_stdin, rx_stdout, rx_stderr) = tokio::join! {
-        wait(&mut socket, session_id),
-        write_stdin(local_stdin, &stdin_data[..]),
-        read_ssh_pipe(local_stdout),
-        read_ssh_pipe(local_stderr),
+/// Runs a given shell command on the remote hosts default shell
+/// though an existing SSH UNIX control socket.
+/// The SSH control socket is created
+/// outside of this crate by an existing SSH connection.
+///
+/// This function is the same as `run_reader` but stdout and stderr of
+/// the remote command are written to the given sinks instead of being
+/// collected in memory.
+pub async fn run_sinks<R, O, E>(
+    ctlpath: &str,
+    command: &str,
+    stdin: R,
+    stdout: &mut O,
+    stderr: &mut E,
+    options: &SessionOptions,
+) -> Result<SinkResult, SshctlError>
+where
+    R: AsyncRead + Unpin,
+    O: AsyncWrite + Unpin,
+    E: AsyncWrite + Unpin,
+{
+    let mut child = spawn(ctlpath, command, options).await?;
+    let local_stdin = child.stdin.take();
+    let local_stdout = child.stdout.take();
+    let local_stderr = child.stderr.take();
+
+    // The execution timeout also applies to copying the output.
+    let result = until(child.deadline(), async {
+        tokio::join! {
+            child.wait_exit(),
+            write_stdin(local_stdin, stdin),
+            copy_ssh_pipe(local_stdout, stdout),
+            copy_ssh_pipe(local_stderr, stderr),
+        }
+    })
+    .await;
+
+    let (rx_rc, tx_stdin, rx_stdout, rx_stderr) = match result {
+        Some(x) => x,
+        None => return Err(child.timed_out().await),
     };
 
     tx_stdin?;
 
-    Ok(ShellResult {
-        stdout: rx_stdout?,
-        stderr: rx_stderr?,
+    Ok(SinkResult {
+        stdout_len: rx_stdout?,
+        stderr_len: rx_stderr?,
         exit_code: rx_rc?,
     })
 }
 
-async fn read_packet_response(
-    socket: &mut UnixStream,
+/// Checks that the SSH master process which owns the given
+/// SSH UNIX control socket is running and returns information about it.
+/// The protocol version of the master is reported even if the other
+/// functions of this crate do not support it.
+///
+/// This is the same as `ssh -O check`.
+pub async fn check(ctlpath: &str) -> Result<MasterInfo, SshctlError> {
+    let mut socket = UnixStream::connect(ctlpath).await?;
+
+    // Unlike the other requests, a different protocol version is
+    // reported instead of rejected.
+    let (version, extensions) = exchange_hello(&mut socket, Vec::new()).await?;
+    let pid = check_mux_alive(&mut socket, 0).await?;
+
+    Ok(MasterInfo {
+        pid,
+        version,
+        extensions,
+    })
+}
+
+/// Requests termination of the SSH master process which owns
+/// the given SSH UNIX control socket.
+///
+/// This is the same as `ssh -O exit`.
+pub async fn terminate(ctlpath: &str) -> Result<(), SshctlError> {
+    let mut socket = UnixStream::connect(ctlpath).await?;
+
+    hello(&mut socket).await?;
+
+    let request_id = 0;
+    let command = MuxCmdMessage {
+        request: MUX_TERMINATE,
+        param: request_id,
+    };
+
+    if let Err(e) = write_command(&mut socket, &command).await {
+        return Err(MuxError::new(format!(
+            "Write terminate request failed: {:?}",
+            e
+        ))
+        .into());
+    }
+
+    match read_status(&mut socket, request_id).await {
+        // The master may exit before its reply was received.
+        Err(SshctlError::IoError(e))
+            if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+        {
+            Ok(())
+        }
+        x => x,
+    }
+}
+
+/// Requests the SSH master process which owns the given SSH UNIX
+/// control socket to stop accepting new multiplexing clients.
+/// Already running sessions are not affected.
+///
+/// This is the same as `ssh -O stop`.
+pub async fn stop_listening(ctlpath: &str) -> Result<(), SshctlError> {
+    let mut socket = UnixStream::connect(ctlpath).await?;
+
+    hello(&mut socket).await?;
+
+    let request_id = 0;
+    let command = MuxCmdMessage {
+        request: MUX_STOP_LISTENING,
+        param: request_id,
+    };
+
+    if let Err(e) = write_command(&mut socket, &command).await {
+        return Err(MuxError::new(format!(
+            "Write stop listening request failed: {:?}",
+            e
+        ))
+        .into());
+    }
+
+    read_status(&mut socket, request_id).await
+}
+
+/// Starts the given subsystem, e.g. "sftp", on the remote host
+/// though an existing SSH UNIX control socket.
+/// The stdin and stdout of the subsystem are available from the
+/// returned stream, stderr is discarded.
+///
+/// This is the same as `ssh -s`.
+pub async fn subsystem(
+    ctlpath: &str,
+    name: &str,
+) -> Result<StdioStream, SshctlError> {
+    let mut socket = UnixStream::connect(ctlpath).await?;
+    hello(&mut socket).await?;
+
+    let request_id = 0;
+    let command = MuxCmdNewSession::new(request_id, name.into()).subsystem();
+
+    let (remote_stdin, local_stdin) = tokio_pipe::pipe()?;
+    let (local_stdout, remote_stdout) = tokio_pipe::pipe()?;
+    let remote_stderr = OpenOptions::new().write(true).open("/dev/null")?;
+
+    open_session(
+        &mut socket,
+        request_id,
+        &command,
+        [
+            remote_stdin.as_raw_fd(),
+            remote_stdout.as_raw_fd(),
+            remote_stderr.as_raw_fd(),
+        ],
+    )
+    .await?;
+
+    Ok(StdioStream::new(socket, local_stdout, local_stdin))
+}
+
+pub(crate) async fn read_packet_response<T: AsyncRead + Unpin>(
+    socket: &mut T,
 ) -> Result<Vec<u8>, std::io::Error> {
     let mut buffer = [0; 4];
 
@@ -109,7 +386,7 @@ async fn read_packet_response(
     Ok(response)
 }
 
-async fn write_command<T: MuxCmd>(
+pub(crate) async fn write_command<T: MuxCmd>(
     socket: &mut UnixStream,
     command: &T,
 ) -> Result<(), std::io::Error> {
@@ -123,7 +400,38 @@ async fn write_command<T: MuxCmd>(
     socket.write(&buffer).await.map(|_| ())
 }
 
-async fn hello(socket: &mut UnixStream) -> Result<(), SshctlError> {
+pub(crate) async fn hello(
+    socket: &mut UnixStream,
+) -> Result<HashMap<String, String>, SshctlError> {
+    hello_extensions(socket, Vec::new()).await
+}
+
+/// Performs the hello exchange and announces the given extensions
+/// to the SSH master. Returns the extensions of the SSH master.
+/// Fails if the master does not support `MUX_VERSION`.
+pub(crate) async fn hello_extensions(
+    socket: &mut UnixStream,
+    extensions: Vec<(String, String)>,
+) -> Result<HashMap<String, String>, SshctlError> {
+    let (version, master_extensions) =
+        exchange_hello(socket, extensions).await?;
+
+    if version != MUX_VERSION {
+        return Err(MuxError::new(format!(
+            "Unsupported protocol version: {}",
+            version
+        ))
+        .into());
+    }
+    Ok(master_extensions)
+}
+
+/// Same as `hello_extensions` but accepts any protocol version
+/// and returns it together with the extensions of the SSH master.
+pub(crate) async fn exchange_hello(
+    socket: &mut UnixStream,
+    extensions: Vec<(String, String)>,
+) -> Result<(u32, HashMap<String, String>), SshctlError> {
     //#[cfg(debug_assertions)]
     //eprintln!("Mux::hello");
 
@@ -146,7 +454,7 @@ async fn hello(socket: &mut UnixStream) -> Result<(), SshctlError> {
         .into());
     }
 
-    let command = MuxCmdHello {};
+    let command = MuxCmdHello::new(extensions);
 
     if let Err(e) = write_command(socket, &command).await {
         return Err(MuxError::new(format!(
@@ -155,10 +463,49 @@ async fn hello(socket: &mut UnixStream) -> Result<(), SshctlError> {
         ))
         .into());
     }
-    Ok(())
+    Ok((response.version(), response.into_extensions()))
 }
 
-async fn check_mux_alive(
+pub(crate) async fn read_status(
+    socket: &mut UnixStream,
+    request_id: u32,
+) -> Result<(), SshctlError> {
+    let response = match read_packet_response(socket).await {
+        Ok(x) => MuxRespStatus::deserialize(&mut x.as_slice())?,
+        Err(e) => return Err(e.into()),
+    };
+
+    if !response.is_valid(request_id) {
+        return Err(MuxError::new(format!(
+            "Received invalid status message: {:?}",
+            response
+        ))
+        .into());
+    }
+
+    check_status(response.cmd(), request_id, response.reason())
+}
+
+pub(crate) fn check_status(
+    cmd: u32,
+    request_id: u32,
+    reason: &str,
+) -> Result<(), SshctlError> {
+    match cmd {
+        MUX_PERMISSION_DENIED => Err(SshctlError::PermissionDenied {
+            request_id,
+            reason: reason.into(),
+        }),
+        MUX_FAILURE => Err(SshctlError::Failure {
+            request_id,
+            reason: reason.into(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the process ID of the SSH master.
+pub(crate) async fn check_mux_alive(
     socket: &mut UnixStream,
     request_id: u32,
 ) -> Result<u32, SshctlError> {
@@ -194,20 +541,62 @@ async fn check_mux_alive(
         .into());
     }
 
-    Ok(request_id + 1)
+    check_status(response.cmd(), request_id, response.reason())?;
+
+    Ok(response.ssh_pid())
 }
 
-async fn new_session(
+pub(crate) async fn new_session(
     socket: &mut UnixStream,
     request_id: u32,
     command: &str,
-) -> Result<(u32, PipeWrite, PipeRead, PipeRead), SshctlError> {
+    options: &SessionOptions,
+    stdio: &[Stdio; 3],
+) -> Result<
+    (u32, Option<PipeWrite>, Option<PipeRead>, Option<PipeRead>),
+    SshctlError,
+> {
     //#[cfg(debug_assertions)]
     //eprintln!("Mux::new_session");
 
-    let command = MuxCmdNewSession::new(request_id, command.into());
+    let mut command = MuxCmdNewSession::new(request_id, command.into())
+        .env(options.env_strings())
+        .forward_agent(options.agent_forwarding())
+        .forward_x11(options.x11_forwarding());
+    if let Some(term) = options.term() {
+        command = command.tty(term.into());
+    }
 
-    if let Err(e) = write_command(socket, &command).await {
+    let (remote_stdin, local_stdin) = stdio[0].input()?;
+    let (remote_stdout, local_stdout) =
+        stdio[1].output(std::io::stdout().as_raw_fd())?;
+    let (remote_stderr, local_stderr) =
+        stdio[2].output(std::io::stderr().as_raw_fd())?;
+
+    let session_id = open_session(
+        socket,
+        request_id,
+        &command,
+        [
+            remote_stdin.as_raw_fd(),
+            remote_stdout.as_raw_fd(),
+            remote_stderr.as_raw_fd(),
+        ],
+    )
+    .await?;
+
+    Ok((session_id, local_stdin, local_stdout, local_stderr))
+}
+
+/// Sends a new session request with the given stdin, stdout and stderr
+/// file descriptors to the SSH master. Returns the session ID.
+async fn open_session(
+    socket: &mut UnixStream,
+    request_id: u32,
+    command: &MuxCmdNewSession,
+    fds: [RawFd; 3],
+) -> Result<u32, SshctlError> {
+    if let Err(e) = write_command(socket, command).await {
         return Err(MuxError::new(format!(
             "Write new session request failed: {:?}",
             e
@@ -215,29 +604,8 @@ async fn new_session(
         .into());
     }
 
-    let (remote_stdin, local_stdin) = tokio_pipe::pipe()?;
-    let (local_stdout, remote_stdout) = tokio_pipe::pipe()?;
-    let (local_stderr, remote_stderr) = tokio_pipe::pipe()?;
-
-    let fds: [i32; 1] = [remote_stdin.as_raw_fd()];
-    if let Err(e) = socket.send_with_fd(b" ", &fds) {
-        return Err(
-            MuxError::new(format!("send_with_fd failed: {:?}", e)).into()
-        );
-    }
-
-    let fds: [i32; 1] = [remote_stdout.as_raw_fd()];
-    if let Err(e) = socket.send_with_fd(b" ", &fds) {
-        return Err(
-            MuxError::new(format!("send_with_fd failed: {:?}", e)).into()
-        );
-    }
-
-    let fds: [i32; 1] = [remote_stderr.as_raw_fd()];
-    if let Err(e) = socket.send_with_fd(b" ", &fds) {
-        return Err(
-            MuxError::new(format!("send_with_fd failed: {:?}", e)).into()
-        );
+    for fd in &fds {
+        send_fd(socket, *fd)?;
     }
 
     let response = match read_packet_response(socket).await {
@@ -259,63 +627,97 @@ async fn new_session(
         .into());
     }
 
-    Ok((
-        response.session_id(),
-        local_stdin,
-        local_stdout,
-        local_stderr,
-    ))
+    check_status(response.cmd(), request_id, response.reason())?;
+
+    Ok(response.session_id())
 }
 
-async fn wait(
+pub(crate) fn send_fd(
+    socket: &UnixStream,
+    fd: RawFd,
+) -> Result<(), SshctlError> {
+    let fds: [RawFd; 1] = [fd];
+    if let Err(e) = socket.send_with_fd(b" ", &fds) {
+        return Err(
+            MuxError::new(format!("send_with_fd failed: {:?}", e)).into()
+        );
+    }
+    Ok(())
+}
+
+pub(crate) async fn wait(
     socket: &mut UnixStream,
     session_id: u32,
 ) -> Result<u32, SshctlError> {
-    let response = match read_packet_response(socket).await {
-        Ok(x) => MuxRespExit::deserialize(&mut x.as_slice())?,
-        Err(e) => {
+    loop {
+        let response = match read_packet_response(socket).await {
+            Ok(x) => MuxRespExit::deserialize(&mut x.as_slice())?,
+            Err(e) => {
+                return Err(MuxError::new(format!(
+                    "Read MuxRespExit failed: {:?}",
+                    e
+                ))
+                .into())
+            }
+        };
+
+        // Like ssh, the command keeps running without a terminal.
+        if response.is_tty_alloc_fail(session_id) {
+            continue;
+        }
+
+        if !response.is_valid(session_id) {
             return Err(MuxError::new(format!(
-                "Read MuxRespExit failed: {:?}",
-                e
+                "Received invalid exit message: {:?}",
+                response
             ))
-            .into())
+            .into());
         }
-    };
 
-    if !response.is_valid(session_id) {
-        return Err(MuxError::new(format!(
-            "Received invalid exit message: {:?}",
-            response
-        ))
-        .into());
+        return Ok(response.exit_code());
     }
-
-    Ok(response.exit_code())
 }
 
-async fn write_stdin(
-    mut local_stdin: PipeWrite,
-    buffer: &[u8],
+async fn write_stdin<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    local_stdin: Option<W>,
+    mut reader: R,
 ) -> Result<(), MuxError> {
-    if let Err(e) = local_stdin.write_all(buffer).await {
-        return Err(MuxError::new(format!("Write stdin failed: {:?}", e)));
+    if let Some(mut local_stdin) = local_stdin {
+        if let Err(e) = io::copy(&mut reader, &mut local_stdin).await {
+            return Err(MuxError::new(format!("Write stdin failed: {:?}", e)));
+        }
     }
     Ok(())
 }
 
-async fn read_ssh_pipe(mut pipe: PipeRead) -> Result<Vec<u8>, MuxError> {
+async fn copy_ssh_pipe<T: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    pipe: Option<T>,
+    sink: &mut W,
+) -> Result<u64, MuxError> {
+    match pipe {
+        Some(mut pipe) => match io::copy(&mut pipe, sink).await {
+            Ok(count) => Ok(count),
+            Err(e) => {
+                Err(MuxError::new(format!("Copy output failed: {:?}", e)))
+            }
+        },
+        None => Ok(0),
+    }
+}
+
+/// Reads a pipe until EOF and applies the given limit.
+/// Returns the data and if it was truncated. Reading stops early
+/// if an `OutputLimit::Abort` limit is exceeded.
+pub(crate) async fn read_ssh_pipe<T: AsyncRead + Unpin>(
+    mut pipe: T,
+    limit: Option<OutputLimit>,
+) -> Result<(Vec<u8>, bool), MuxError> {
     let mut data = Vec::<u8>::with_capacity(1024);
     let mut buffer = [0; 1024];
+    let mut truncated = false;
     loop {
-        match pipe.read(&mut buffer).await {
-            Ok(count) => {
-                //#[cfg(debug_assertions)]
-                //eprintln!("received from pipe: {}: {:?}", count, data);
-                if count == 0 {
-                    return Ok(data);
-                }
-                data.append(&mut buffer[..count].to_vec());
-            }
+        let count = match pipe.read(&mut buffer).await {
+            Ok(count) => count,
             Err(e) => {
                 return Err(MuxError::new(format!(
                     "Read stdout failed: {:?}",
@@ -323,5 +725,43 @@ async fn read_ssh_pipe(mut pipe: PipeRead) -> Result<Vec<u8>, MuxError> {
                 )))
             }
         };
+        //#[cfg(debug_assertions)]
+        //eprintln!("received from pipe: {}: {:?}", count, data);
+        if count == 0 {
+            break;
+        }
+
+        match limit {
+            None => data.extend_from_slice(&buffer[..count]),
+            Some(OutputLimit::Truncate(max))
+            | Some(OutputLimit::Abort(max)) => {
+                let room = max.saturating_sub(data.len());
+                if count > room {
+                    data.extend_from_slice(&buffer[..room]);
+                    truncated = true;
+                    if let Some(OutputLimit::Abort(_)) = limit {
+                        return Ok((data, truncated));
+                    }
+                } else {
+                    data.extend_from_slice(&buffer[..count]);
+                }
+            }
+            Some(OutputLimit::KeepTail(max)) => {
+                data.extend_from_slice(&buffer[..count]);
+                // Drain in batches to avoid moving the data on every read.
+                if data.len() > 2 * max + buffer.len() {
+                    data.drain(..data.len() - max);
+                    truncated = true;
+                }
+            }
+        }
+    }
+
+    if let Some(OutputLimit::KeepTail(max)) = limit {
+        if data.len() > max {
+            data.drain(..data.len() - max);
+            truncated = true;
+        }
     }
+    Ok((data, truncated))
 }
@@ -1,5 +1,28 @@
-use crate::session::{run, ShellResult};
+use crate::child::{parse_pid_line, spawn, PidLine, Stdio};
+use crate::commands::{
+    MuxCmd, MuxCmdNewSession, MuxRespCheckAlive, MuxRespHello,
+    MuxRespNewSession, MuxRespOpenForward, MuxRespStatus,
+};
+use crate::forward::{
+    allocated_port, forward_dynamic, forward_local, forward_remote,
+    forward_stdio,
+};
+use crate::lines::split_lines;
+use crate::options::{
+    match_pattern, OutputLimit, OutputSource, SessionOptions, TimeoutPhase,
+};
+use crate::proxy::{ChannelEvent, ProxyConnection};
+use crate::remote_command::RemoteCommand;
+use crate::session::{
+    check, check_status, read_ssh_pipe, run, run_file, run_reader, run_sinks,
+    run_with_options, subsystem, LimitedResult, ShellResult, SinkResult,
+};
 use crate::SshctlError;
+use bytes::BytesMut;
+use std::collections::VecDeque;
+use std::path::Path;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
 use tokio::time::{self, Duration};
 
 /*
@@ -14,7 +37,7 @@ use tokio::time::{self, Duration};
  *   ssh dummy
  */
 
-const TEST_SOCKET: &'static str = "/tmp/test.sock";
+const TEST_SOCKET: &str = "/tmp/test.sock";
 
 #[tokio::test]
 async fn test_connect_echo() -> Result<(), SshctlError> {
@@ -28,6 +51,15 @@ async fn test_connect_echo() -> Result<(), SshctlError> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_check() -> Result<(), SshctlError> {
+    let info = check(TEST_SOCKET).await?;
+
+    assert_eq!(4, info.version);
+    assert!(Path::new(&format!("/proc/{}", info.pid)).exists());
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_abort_cmd_with_timeout() -> Result<(), SshctlError> {
     match time::timeout(
@@ -53,6 +85,55 @@ async fn test_abort_cmd_with_timeout() -> Result<(), SshctlError> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_timeout_kills_process_group() -> Result<(), SshctlError> {
+    let result = time::timeout(
+        Duration::from_secs(1),
+        run(TEST_SOCKET, "sleep 4242 | sleep 4243"),
+    )
+    .await;
+    assert!(result.is_err());
+
+    // The process group is killed in the background.
+    time::sleep(Duration::from_secs(2)).await;
+
+    // The pattern does not match the command line of pgrep itself.
+    let result = run(TEST_SOCKET, "pgrep -f 'sleep 424[23]'").await?;
+    assert_eq!(1, result.exit_code);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_exec_timeout() -> Result<(), SshctlError> {
+    let options = SessionOptions::new().exec_timeout(Duration::from_secs(1));
+    match run_with_options(
+        TEST_SOCKET,
+        "sleep 4244 | sleep 4245",
+        None,
+        &options,
+    )
+    .await
+    {
+        Err(SshctlError::Timeout(TimeoutPhase::Execution)) => (),
+        x => panic!("not timed out: {:?}", x),
+    }
+
+    let result = run(TEST_SOCKET, "pgrep -f 'sleep 424[45]'").await?;
+    assert_eq!(1, result.exit_code);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_run_without_pid_report() -> Result<(), SshctlError> {
+    let options = SessionOptions::new().report_pid(false);
+    let result =
+        run_with_options(TEST_SOCKET, "echo asdf", None, &options).await?;
+
+    assert_eq!(b"asdf\n".to_vec(), result.stdout);
+    assert_eq!(0, result.exit_code);
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_read_large_data() -> Result<(), SshctlError> {
     let result = run(
@@ -101,3 +182,606 @@ async fn test_parallel_commands() -> Result<(), SshctlError> {
     assert_eq!(expectation3, result3?);
     Ok(())
 }
+
+#[tokio::test]
+async fn test_spawn_streams() -> Result<(), SshctlError> {
+    let mut child = spawn(TEST_SOCKET, "cat", &SessionOptions::new()).await?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"asdf\n").await?;
+    drop(stdin);
+
+    let mut stdout = Vec::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_end(&mut stdout)
+        .await?;
+
+    assert_eq!(b"asdf\n".to_vec(), stdout);
+    assert_eq!(0, child.wait().await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_spawn_kill() -> Result<(), SshctlError> {
+    let mut child =
+        spawn(TEST_SOCKET, "sleep 10", &SessionOptions::new()).await?;
+    assert_ne!(None, child.pid().await?);
+
+    match time::timeout(Duration::from_secs(5), child.kill()).await {
+        Err(e) => panic!("kill timed out: {:?}", e),
+        Ok(x) => x,
+    }
+}
+
+#[test]
+fn test_pid_line() {
+    let prefix = b"ssh-muxcontrol-0123456789abcdef ";
+
+    assert_eq!(PidLine::Incomplete, parse_pid_line(prefix, b""));
+    assert_eq!(PidLine::Incomplete, parse_pid_line(prefix, b"ssh-mux"));
+    assert_eq!(
+        PidLine::Incomplete,
+        parse_pid_line(prefix, b"ssh-muxcontrol-0123456789abcdef 42\r")
+    );
+    assert_eq!(
+        PidLine::Found(35, 42),
+        parse_pid_line(prefix, b"ssh-muxcontrol-0123456789abcdef 42\nasdf")
+    );
+    assert_eq!(
+        PidLine::Found(36, 42),
+        parse_pid_line(prefix, b"ssh-muxcontrol-0123456789abcdef 42\r\n")
+    );
+    assert_eq!(PidLine::Mismatch, parse_pid_line(prefix, b"asdf\n"));
+    assert_eq!(PidLine::Mismatch, parse_pid_line(prefix, b"ssh-muxctl"));
+    assert_eq!(
+        PidLine::Mismatch,
+        parse_pid_line(prefix, b"ssh-muxcontrol-0123456789abcdef \n")
+    );
+    assert_eq!(
+        PidLine::Mismatch,
+        parse_pid_line(prefix, b"ssh-muxcontrol-0123456789abcdef 4x\n")
+    );
+    assert_eq!(
+        PidLine::Mismatch,
+        parse_pid_line(prefix, b"ssh-muxcontrol-0123456789abcdef 99999999999")
+    );
+}
+
+#[tokio::test]
+async fn test_remote_command_output() -> Result<(), SshctlError> {
+    let result = RemoteCommand::new("printf")
+        .args(["%s|%s\\n", "it's", "$HOME"])
+        .env("FOO", "bar baz")
+        .current_dir("/")
+        .stderr(Stdio::null())
+        .output(TEST_SOCKET)
+        .await?;
+
+    assert_eq!(b"it's|$HOME\n".to_vec(), result.stdout);
+    assert_eq!(0, result.exit_code);
+    Ok(())
+}
+
+#[test]
+fn test_remote_command_line() -> Result<(), SshctlError> {
+    let command = RemoteCommand::new("ls")
+        .arg("it's here")
+        .env("LC_ALL", "C")
+        .current_dir("/tmp");
+
+    assert_eq!(
+        "cd '/tmp' && LC_ALL='C' 'ls' 'it'\\''s here'",
+        command.command_line()?
+    );
+    assert!(RemoteCommand::new("ls")
+        .env("A;B", "C")
+        .command_line()
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_run_reader() -> Result<(), SshctlError> {
+    // Much larger than the pipe buffer.
+    let stdin = io::repeat(b'a').take(16 * 1024 * 1024);
+    let result =
+        run_reader(TEST_SOCKET, "wc -c", stdin, &SessionOptions::new()).await?;
+
+    assert_eq!(b"16777216\n".to_vec(), result.stdout);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_run_file() -> Result<(), SshctlError> {
+    let length = std::fs::metadata("Cargo.toml")?.len();
+    let result =
+        run_file(TEST_SOCKET, "wc -c", "Cargo.toml", &SessionOptions::new())
+            .await?;
+
+    assert_eq!(format!("{}\n", length).into_bytes(), result.stdout);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_run_sinks() -> Result<(), SshctlError> {
+    let mut stdout = io::sink();
+    let mut stderr = Vec::new();
+    let result = run_sinks(
+        TEST_SOCKET,
+        "head -c 16777216 /dev/zero && echo asdf >&2",
+        io::empty(),
+        &mut stdout,
+        &mut stderr,
+        &SessionOptions::new(),
+    )
+    .await?;
+
+    let expectation = SinkResult {
+        stdout_len: 16777216,
+        stderr_len: 5,
+        exit_code: 0,
+    };
+    assert_eq!(expectation, result);
+    assert_eq!(b"asdf\n".to_vec(), stderr);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_output_limits() -> Result<(), SshctlError> {
+    let options = SessionOptions::new()
+        .stdout_limit(OutputLimit::Truncate(4))
+        .stderr_limit(OutputLimit::KeepTail(4));
+    let result = run_with_options(
+        TEST_SOCKET,
+        "echo 123456789 && echo 123456789 >&2",
+        None,
+        &options,
+    )
+    .await?;
+
+    let expectation = LimitedResult {
+        stdout: "1234".into(),
+        stderr: "789\n".into(),
+        exit_code: 0,
+        stdout_truncated: true,
+        stderr_truncated: true,
+    };
+    assert_eq!(expectation, result);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_output_limit_abort() -> Result<(), SshctlError> {
+    let options =
+        SessionOptions::new().stdout_limit(OutputLimit::Abort(1024 * 1024));
+    match run_with_options(TEST_SOCKET, "yes", None, &options).await {
+        Err(SshctlError::OutputLimitExceeded(OutputSource::Stdout)) => Ok(()),
+        x => panic!("output limit not exceeded: {:?}", x),
+    }
+}
+
+#[tokio::test]
+async fn test_read_ssh_pipe_limits() -> Result<(), SshctlError> {
+    let data: Vec<u8> = (0..10000).map(|x| x as u8).collect();
+
+    let (head, truncated) =
+        read_ssh_pipe(&data[..], Some(OutputLimit::Truncate(100))).await?;
+    assert_eq!(&data[..100], &head[..]);
+    assert!(truncated);
+
+    let (tail, truncated) =
+        read_ssh_pipe(&data[..], Some(OutputLimit::KeepTail(100))).await?;
+    assert_eq!(&data[9900..], &tail[..]);
+    assert!(truncated);
+
+    let (all, truncated) =
+        read_ssh_pipe(&data[..], Some(OutputLimit::Abort(10000))).await?;
+    assert_eq!(data, all);
+    assert!(!truncated);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_output_lines() -> Result<(), SshctlError> {
+    let mut child = spawn(
+        TEST_SOCKET,
+        "echo 1234 && sleep 1 && echo 2345 >&2 && sleep 1 && printf 34567",
+        &SessionOptions::new(),
+    )
+    .await?;
+
+    let mut lines = Vec::new();
+    let mut output = child.output_lines(4);
+    while let Some(line) = output.read().await {
+        let line = line?;
+        lines.push((line.source, line.line));
+    }
+
+    let expectation = vec![
+        (OutputSource::Stdout, b"1234".to_vec()),
+        (OutputSource::Stderr, b"2345".to_vec()),
+        (OutputSource::Stdout, b"3456".to_vec()),
+        (OutputSource::Stdout, b"7".to_vec()),
+    ];
+    assert_eq!(expectation, lines);
+    assert_eq!(0, child.wait().await?);
+    Ok(())
+}
+
+#[test]
+fn test_split_lines() {
+    let mut partial = Vec::new();
+    let mut pending = VecDeque::new();
+    split_lines(
+        b"ab\n\nabcd\nabcdef",
+        OutputSource::Stdout,
+        &mut partial,
+        4,
+        &mut pending,
+    );
+
+    let lines: Vec<Vec<u8>> =
+        pending.into_iter().map(|line| line.line).collect();
+    let expectation: Vec<Vec<u8>> = vec![
+        b"ab".to_vec(),
+        b"".to_vec(),
+        b"abcd".to_vec(),
+        b"abcd".to_vec(),
+    ];
+    assert_eq!(expectation, lines);
+    assert_eq!(b"ef".to_vec(), partial);
+}
+
+#[tokio::test]
+async fn test_session_env() -> Result<(), SshctlError> {
+    // Requires "SendEnv LC_*" on the master and "AcceptEnv LC_*" on the
+    // server, which is the default on many distributions.
+    let options = SessionOptions::new().env("LC_MUXCONTROL_TEST", "asdf");
+    let result = run_with_options(
+        TEST_SOCKET,
+        "echo $LC_MUXCONTROL_TEST",
+        None,
+        &options,
+    )
+    .await?;
+
+    assert_eq!(b"asdf\n".to_vec(), result.stdout);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_session_tty() -> Result<(), SshctlError> {
+    let options = SessionOptions::new().tty("xterm");
+    let result =
+        run_with_options(TEST_SOCKET, "tty -s && echo $TERM", None, &options)
+            .await?;
+
+    // The terminal translates line endings.
+    assert_eq!(b"xterm\r\n".to_vec(), result.stdout);
+    assert_eq!(0, result.exit_code);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_session_forward_agent() -> Result<(), SshctlError> {
+    // Requires "ForwardAgent yes" on the master. Otherwise the request
+    // is dropped silently and SSH_AUTH_SOCK is missing.
+    let options = SessionOptions::new().forward_agent(true);
+    let result = run_with_options(
+        TEST_SOCKET,
+        "test -S \"$SSH_AUTH_SOCK\"",
+        None,
+        &options,
+    )
+    .await?;
+
+    assert_eq!(0, result.exit_code);
+    Ok(())
+}
+
+#[test]
+fn test_new_session_forwarding_flags() {
+    let command = MuxCmdNewSession::new(3, "true".into())
+        .forward_agent(true)
+        .forward_x11(false);
+    let mut buffer = BytesMut::new();
+    command.serialize(&mut buffer);
+
+    // Request ID, empty reserved string, tty, X11 and agent flags.
+    assert_eq!(buffer.len(), command.length());
+    assert_eq!([0, 0, 0, 3], buffer[4..8]);
+    assert_eq!([0, 0, 0, 0], buffer[8..12]);
+    assert_eq!([0, 0, 0, 0], buffer[12..16]);
+    assert_eq!([0, 0, 0, 0], buffer[16..20]);
+    assert_eq!([0, 0, 0, 1], buffer[20..24]);
+}
+
+#[test]
+fn test_new_session_refused() -> Result<(), SshctlError> {
+    // MUX_S_PERMISSION_DENIED for request 7 with reason "denied"
+    let reply = [
+        0x80, 0, 0, 2, 0, 0, 0, 7, 0, 0, 0, 6, b'd', b'e', b'n', b'i', b'e',
+        b'd',
+    ];
+    let response = MuxRespNewSession::deserialize(&mut &reply[..])?;
+
+    match check_status(response.cmd(), 7, response.reason()) {
+        Err(SshctlError::PermissionDenied { request_id, reason }) => {
+            assert_eq!(7, request_id);
+            assert_eq!("denied", reason);
+        }
+        x => panic!("unexpected status: {:?}", x),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_alive_failure() -> Result<(), SshctlError> {
+    // MUX_S_FAILURE for request 1 with reason "failed"
+    let reply = [
+        0x80, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 6, b'f', b'a', b'i', b'l', b'e',
+        b'd',
+    ];
+    let response = MuxRespCheckAlive::deserialize(&mut &reply[..])?;
+
+    match check_status(response.cmd(), 1, response.reason()) {
+        Err(SshctlError::Failure { request_id, reason }) => {
+            assert_eq!(1, request_id);
+            assert_eq!("failed", reason);
+        }
+        x => panic!("unexpected status: {:?}", x),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_hello_other_version() -> Result<(), SshctlError> {
+    // MUX_MSG_HELLO with version 5 and extension "a" = "b"
+    let reply = [0, 0, 0, 1, 0, 0, 0, 5, 0, 0, 0, 1, b'a', 0, 0, 0, 1, b'b'];
+    let response = MuxRespHello::deserialize(&mut &reply[..])?;
+
+    assert!(response.is_valid());
+    assert_eq!(5, response.version());
+    assert_eq!(
+        Some("b"),
+        response.into_extensions().get("a").map(|x| &x[..])
+    );
+    Ok(())
+}
+
+#[test]
+fn test_status_ok() -> Result<(), SshctlError> {
+    // MUX_S_OK for request 3
+    let reply = [0x80, 0, 0, 1, 0, 0, 0, 3];
+    let response = MuxRespStatus::deserialize(&mut &reply[..])?;
+
+    assert!(response.is_valid(3));
+    assert!(!response.is_valid(4));
+    check_status(response.cmd(), 3, response.reason())
+}
+
+#[test]
+fn test_status_permission_denied() -> Result<(), SshctlError> {
+    // MUX_S_PERMISSION_DENIED for request 0 with reason "denied"
+    let reply = [
+        0x80, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 6, b'd', b'e', b'n', b'i', b'e',
+        b'd',
+    ];
+    let response = MuxRespStatus::deserialize(&mut &reply[..])?;
+    assert!(response.is_valid(0));
+
+    match check_status(response.cmd(), 0, response.reason()) {
+        Err(SshctlError::PermissionDenied { request_id, reason }) => {
+            assert_eq!(0, request_id);
+            assert_eq!("denied", reason);
+        }
+        x => panic!("unexpected status: {:?}", x),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_status_failure() -> Result<(), SshctlError> {
+    // MUX_S_FAILURE for request 2 with reason "failed"
+    let reply = [
+        0x80, 0, 0, 3, 0, 0, 0, 2, 0, 0, 0, 6, b'f', b'a', b'i', b'l', b'e',
+        b'd',
+    ];
+    let response = MuxRespStatus::deserialize(&mut &reply[..])?;
+    assert!(!response.is_valid(0));
+
+    match check_status(response.cmd(), 2, response.reason()) {
+        Err(SshctlError::Failure { request_id, reason }) => {
+            assert_eq!(2, request_id);
+            assert_eq!("failed", reason);
+        }
+        x => panic!("unexpected status: {:?}", x),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_status_invalid() {
+    // MUX_S_OK with trailing garbage
+    let reply = [0x80, 0, 0, 1, 0, 0, 0, 0, 0xff];
+    assert!(MuxRespStatus::deserialize(&mut &reply[..]).is_err());
+
+    // MUX_S_FAILURE with truncated reason
+    let reply = [0x80, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 6, b'f'];
+    assert!(MuxRespStatus::deserialize(&mut &reply[..]).is_err());
+
+    // MUX_S_IS_ALIVE is no status reply
+    let reply = [0x80, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 1];
+    assert!(MuxRespStatus::deserialize(&mut &reply[..]).is_err());
+}
+
+#[test]
+fn test_open_forward_remote_port() -> Result<(), SshctlError> {
+    // MUX_S_REMOTE_PORT for request 0 with port 42022
+    let reply = [0x80, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0xa4, 0x26];
+    let response = MuxRespOpenForward::deserialize(&mut &reply[..])?;
+
+    assert!(response.is_valid(0));
+    check_status(response.cmd(), 0, response.reason())?;
+    assert_eq!(Some(42022), allocated_port(&response)?);
+
+    // MUX_S_OK for request 0 without a port
+    let reply = [0x80, 0, 0, 1, 0, 0, 0, 0];
+    let response = MuxRespOpenForward::deserialize(&mut &reply[..])?;
+    assert_eq!(None, allocated_port(&response)?);
+    Ok(())
+}
+
+#[test]
+fn test_open_forward_invalid_port() -> Result<(), SshctlError> {
+    // MUX_S_REMOTE_PORT for request 0 with port 65536
+    let reply = [0x80, 0, 0, 7, 0, 0, 0, 0, 0, 1, 0, 0];
+    let response = MuxRespOpenForward::deserialize(&mut &reply[..])?;
+    assert!(allocated_port(&response).is_err());
+
+    // MUX_S_REMOTE_PORT without a port
+    let reply = [0x80, 0, 0, 7, 0, 0, 0, 0];
+    assert!(MuxRespOpenForward::deserialize(&mut &reply[..]).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_send_env_pattern() {
+    assert!(match_pattern("LC_ALL", "LC_*"));
+    assert!(match_pattern("LANG", "LAN?"));
+    assert!(match_pattern("LANG", "*"));
+    assert!(match_pattern("XMODIFIERS", "*MOD*S"));
+    assert!(!match_pattern("LANGUAGE", "LAN?"));
+    assert!(!match_pattern("PATH", "LC_*"));
+}
+
+#[tokio::test]
+async fn test_forward_local() -> Result<(), SshctlError> {
+    let forwarding =
+        forward_local(TEST_SOCKET, ("127.0.0.1", 42022), ("localhost", 22))
+            .await?;
+
+    let mut stream = TcpStream::connect("127.0.0.1:42022").await?;
+    let mut banner = [0; 8];
+    stream.read_exact(&mut banner).await?;
+
+    assert_eq!(b"SSH-2.0-", &banner);
+    forwarding.close().await
+}
+
+#[tokio::test]
+async fn test_forward_remote_allocated_port() -> Result<(), SshctlError> {
+    let forwarding =
+        forward_remote(TEST_SOCKET, ("", 0), ("localhost", 22)).await?;
+
+    assert_ne!(None, forwarding.allocated_port());
+    forwarding.close().await
+}
+
+#[tokio::test]
+async fn test_forward_dynamic() -> Result<(), SshctlError> {
+    let forwarding = forward_dynamic(TEST_SOCKET, ("127.0.0.1", 42080)).await?;
+
+    // SOCKS5 greeting without authentication.
+    let mut stream = TcpStream::connect("127.0.0.1:42080").await?;
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+
+    assert_eq!([5, 0], reply);
+    forwarding.close().await
+}
+
+#[tokio::test]
+async fn test_close_forward() -> Result<(), SshctlError> {
+    let forwarding =
+        forward_local(TEST_SOCKET, ("127.0.0.1", 42023), ("localhost", 22))
+            .await?;
+    forwarding.close().await?;
+
+    assert!(TcpStream::connect("127.0.0.1:42023").await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_forward_local_streamlocal() -> Result<(), SshctlError> {
+    let path = Path::new("/tmp/test_forward.sock");
+    let forwarding =
+        forward_local(TEST_SOCKET, path, ("localhost", 22)).await?;
+
+    let mut stream = UnixStream::connect(path).await?;
+    let mut banner = [0; 8];
+    stream.read_exact(&mut banner).await?;
+
+    assert_eq!(b"SSH-2.0-", &banner);
+    forwarding.close().await
+}
+
+#[tokio::test]
+async fn test_forward_stdio() -> Result<(), SshctlError> {
+    let mut stream = forward_stdio(TEST_SOCKET, ("localhost", 22)).await?;
+    let mut banner = [0; 8];
+    stream.read_exact(&mut banner).await?;
+
+    assert_eq!(b"SSH-2.0-", &banner);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_subsystem_sftp() -> Result<(), SshctlError> {
+    let mut stream = subsystem(TEST_SOCKET, "sftp").await?;
+
+    // SSH_FXP_INIT with version 3.
+    stream.write_all(&[0, 0, 0, 5, 1, 0, 0, 0, 3]).await?;
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply).await?;
+
+    // SSH_FXP_VERSION
+    assert_eq!(2, reply[4]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_parallel_commands() -> Result<(), SshctlError> {
+    let expectation1 = ShellResult {
+        stdout: "1234\n".into(),
+        stderr: "".into(),
+        exit_code: 0,
+    };
+    let expectation2 = ShellResult {
+        stdout: "".into(),
+        stderr: "2345\n".into(),
+        exit_code: 1,
+    };
+
+    let connection = ProxyConnection::connect(TEST_SOCKET).await?;
+    let (result1, result2) = tokio::join!(
+        connection.run("sleep 1 && echo 1234\n"),
+        connection.run("sleep 1 && echo 2345 >&2 && exit 1\n"),
+    );
+
+    assert_eq!(expectation1, result1?);
+    assert_eq!(expectation2, result2?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_direct() -> Result<(), SshctlError> {
+    let connection = ProxyConnection::connect(TEST_SOCKET).await?;
+    let mut channel = connection.direct(("localhost", 22)).await?;
+
+    let mut banner = Vec::new();
+    while banner.len() < 8 {
+        match channel.read().await? {
+            Some(ChannelEvent::Data(mut data)) => banner.append(&mut data),
+            Some(_) => (),
+            None => break,
+        }
+    }
+
+    assert_eq!(b"SSH-2.0-", &banner[..8]);
+    Ok(())
+}